@@ -22,11 +22,11 @@ fn gen_sg_bindings() {
     extern crate bindgen;
 
     let bindings = bindgen::Builder::default()
-        .header_contents("sg.h", "#include <scsi/sg.h>")
+        .header_contents("sg.h", "#include <scsi/sg.h>\n#include <linux/bsg.h>")
         .derive_debug(true)
         .derive_default(true)
         .generate()
-        .expect("Unable to generate scsi/sg.h bindings");
+        .expect("Unable to generate scsi/sg.h and linux/bsg.h bindings");
     let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
     bindings
         .write_to_file(out_path.join("sg.rs"))
@@ -15,8 +15,21 @@
  *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+pub mod config;
+pub mod convert;
+pub mod filter;
+pub mod firmware;
+pub mod policy;
+pub mod snapshot;
+pub mod update;
+
+use crate::asm2x6x::config::ConfigLayout;
+use crate::asm2x6x::policy::TransferPolicy;
 use crate::error::Error;
+use log::warn;
 use std::fmt::{Display, Formatter};
+use std::ops::Range;
+use std::str::FromStr;
 use std::vec::Vec;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -24,17 +37,97 @@ pub enum Model {
     ASM2464PD,
 }
 
+/// Part of the firmware that is transferred with a single FlashRead/FlashWrite.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlashRegion {
+    pub selector: u8,
+    pub range: Range<usize>,
+}
+
+#[derive(Debug)]
+pub struct ModelInfo {
+    pub model: Model,
+    pub name: &'static str,
+    pub usb_ids: &'static [(u16, u16)],
+    /// SCSI INQUIRY vendor and product identification prefixes
+    pub inquiry: (&'static str, &'static str),
+    /// size of the firmware area in flash
+    pub flash_size: usize,
+    pub firmware_regions: &'static [FlashRegion],
+    pub config_layout: &'static ConfigLayout,
+}
+
+// selectors and split point used by read_firmware for the ASM2464PD before the model table
+const ASM246X_FIRMWARE_REGIONS: &[FlashRegion] = &[
+    FlashRegion {
+        selector: 0x50,
+        range: 0x0..0xff00,
+    },
+    FlashRegion {
+        selector: 0xd0,
+        range: 0xff00..0x17ee0,
+    },
+];
+
+/// All supported models. Lookups return the first match, so models sharing USB IDs or
+/// INQUIRY strings with a more common sibling have to come after it.
+///
+/// The ASM2464PD entry holds what the tool used before the model table: the USB ID matched
+/// by `usb::find_devices`, the INQUIRY strings matched by `linux::find_devices` and the
+/// flash regions read by `read_firmware`.
+pub const MODELS: &[ModelInfo] = &[ModelInfo {
+    model: Model::ASM2464PD,
+    name: "ASM2464PD",
+    usb_ids: &[(0x174c, 0x2463)],
+    inquiry: ("ASMT", "ASM246X"),
+    flash_size: 0x17ee0,
+    firmware_regions: ASM246X_FIRMWARE_REGIONS,
+    config_layout: &config::ASM246X_LAYOUT,
+}];
+
+impl Model {
+    pub fn info(&self) -> &'static ModelInfo {
+        MODELS
+            .iter()
+            .find(|info| info.model == *self)
+            .expect("every model has an entry in MODELS")
+    }
+
+    pub fn from_usb_id(vid: u16, pid: u16) -> Option<Model> {
+        MODELS
+            .iter()
+            .find(|info| info.usb_ids.contains(&(vid, pid)))
+            .map(|info| info.model)
+    }
+
+    pub fn from_inquiry(vendor: &str, product: &str) -> Option<Model> {
+        MODELS
+            .iter()
+            .find(|info| vendor.starts_with(info.inquiry.0) && product.starts_with(info.inquiry.1))
+            .map(|info| info.model)
+    }
+}
+
 impl Display for Model {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Model::ASM2464PD => write!(f, "ASM2464PD"),
-        }
+        write!(f, "{}", self.info().name)
+    }
+}
+
+impl FromStr for Model {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        MODELS
+            .iter()
+            .find(|info| info.name.eq_ignore_ascii_case(s))
+            .map(|info| info.model)
+            .ok_or(Error::UnknownModel)
     }
 }
 
-#[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum Command {
+pub(crate) enum Command {
     ConfigRead = 0xe0,
     ConfigWrite = 0xe1,
     FlashRead = 0xe2,
@@ -44,23 +137,126 @@ enum Command {
     Reload = 0xe8,
 }
 
-pub trait Backend {
+impl TryFrom<u8> for Command {
+    type Error = Error;
+
+    fn try_from(opcode: u8) -> Result<Self, Self::Error> {
+        match opcode {
+            0xe0 => Ok(Command::ConfigRead),
+            0xe1 => Ok(Command::ConfigWrite),
+            0xe2 => Ok(Command::FlashRead),
+            0xe3 => Ok(Command::FlashWrite),
+            0xe4 => Ok(Command::Read),
+            0xe5 => Ok(Command::Write),
+            0xe8 => Ok(Command::Reload),
+            _ => Err(Error::InvalidCDB),
+        }
+    }
+}
+
+/// Backends are `Send` so that a `Device` can be moved to a worker thread.
+pub trait Backend: Send {
     fn model(&self) -> Model;
 
     fn transfer(&mut self, cdb: &[u8]) -> Result<(), Error>;
     fn transfer_to_device(&mut self, cdb: &[u8], data: &[u8]) -> Result<(), Error>;
     fn transfer_from_device(&mut self, cdb: &[u8], data: &mut [u8]) -> Result<(), Error>;
+
+    /// Sends a command after which the device is expected to disconnect, e.g. Reload.
+    /// The disconnect itself must not be reported as an error.
+    fn transfer_reset(&mut self, cdb: &[u8]) -> Result<(), Error>;
+
+    /// Backends that support timeouts pick them from the policy for every transfer.
+    fn set_policy(&mut self, _policy: &TransferPolicy) {}
+
+    /// Short description of how commands reach the device, e.g. `USB UAS`.
+    fn transport(&self) -> String {
+        String::from("unknown")
+    }
+}
+
+/// What a backend knows about a device without opening it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceDetails {
+    /// USB vendor and product id
+    pub usb_id: Option<(u16, u16)>,
+    pub serial: Option<String>,
+    /// product revision from the SCSI INQUIRY data
+    pub revision: Option<String>,
+    /// USB bus and chain of hub ports in sysfs notation, e.g. `2-1.3`
+    pub port_path: Option<String>,
+    /// link speed in Mbit/s
+    pub speed: Option<String>,
+}
+
+impl DeviceDetails {
+    /// Identifies the physical device, so that a chip reachable through several backends
+    /// can be told apart from two identical chips. The port path is unique on a host; the
+    /// USB ID and serial number are used if it is not known.
+    pub fn physical_id(&self) -> Option<String> {
+        if let Some(port_path) = &self.port_path {
+            return Some(format!("port {}", port_path));
+        }
+
+        let (vid, pid) = self.usb_id?;
+        let serial = self.serial.as_ref()?;
+        Some(format!("{:04x}:{:04x} {}", vid, pid, serial))
+    }
+}
+
+impl Display for DeviceDetails {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+
+        if let Some((vid, pid)) = self.usb_id {
+            parts.push(format!("{:04x}:{:04x}", vid, pid));
+        }
+        if let Some(port_path) = &self.port_path {
+            parts.push(format!("port {}", port_path));
+        }
+        if let Some(speed) = &self.speed {
+            parts.push(format!("{} Mbit/s", speed));
+        }
+        if let Some(serial) = &self.serial {
+            parts.push(format!("serial {}", serial));
+        }
+        if let Some(revision) = &self.revision {
+            parts.push(format!("rev {}", revision));
+        }
+
+        write!(f, "{}", parts.join(", "))
+    }
 }
 
 pub trait Info: ToString {
     fn model(&self) -> Model;
     fn open(&self) -> Result<Box<dyn Backend>, Error>;
+
+    /// Opens the device with `policy` in effect for every transfer, including those
+    /// made while opening it.
+    fn open_with_policy(&self, policy: &TransferPolicy) -> Result<Box<dyn Backend>, Error> {
+        let mut backend = self.open()?;
+        backend.set_policy(policy);
+        Ok(backend)
+    }
+
+    fn details(&self) -> DeviceDetails {
+        DeviceDetails::default()
+    }
 }
 
+/// Size of the XDATA address space reachable through Read and Write.
+pub const XDATA_SIZE: u32 = 0x20000;
+
+const MAX_READ_CHUNK: usize = 0xff;
+
 pub struct Device {
     backend: Box<dyn Backend>,
+    model: Model,
+    policy: TransferPolicy,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct FWVersion {
     day: u8,
     month: u8,
@@ -70,6 +266,84 @@ pub struct FWVersion {
     c: u8,
 }
 
+impl From<[u8; 6]> for FWVersion {
+    fn from(bfr: [u8; 6]) -> Self {
+        FWVersion {
+            year: bfr[0],
+            month: bfr[1],
+            day: bfr[2],
+            a: bfr[3],
+            b: bfr[4],
+            c: bfr[5],
+        }
+    }
+}
+
+impl FWVersion {
+    /// Checks that the build date is plausible, i.e. a BCD encoded month and day.
+    pub fn date_valid(&self) -> bool {
+        let bcd = |v: u8| (v >> 4) < 10 && (v & 0xf) < 10;
+        let dec = |v: u8| (v >> 4) * 10 + (v & 0xf);
+
+        bcd(self.year)
+            && bcd(self.month)
+            && bcd(self.day)
+            && (1..=12).contains(&dec(self.month))
+            && (1..=31).contains(&dec(self.day))
+    }
+
+    /// Build date as year, month and day, if it is plausible.
+    pub fn build_date(&self) -> Option<(u16, u8, u8)> {
+        let dec = |v: u8| (v >> 4) * 10 + (v & 0xf);
+
+        self.date_valid()
+            .then(|| (2000 + dec(self.year) as u16, dec(self.month), dec(self.day)))
+    }
+
+    /// First byte after the build date. Its meaning is not known.
+    pub fn a(&self) -> u8 {
+        self.a
+    }
+
+    pub fn b(&self) -> u8 {
+        self.b
+    }
+
+    pub fn c(&self) -> u8 {
+        self.c
+    }
+}
+
+impl FromStr for FWVersion {
+    type Err = Error;
+
+    /// Parses the format produced by `Display`, e.g. `240115_00_01_00`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = |s: &str| u8::from_str_radix(s, 16).map_err(|_| Error::InvalidFWVersion);
+
+        let mut parts = s.split('_');
+        let date = parts.next().ok_or(Error::InvalidFWVersion)?;
+        if date.len() != 6 || !date.is_ascii() {
+            return Err(Error::InvalidFWVersion);
+        }
+
+        let version = FWVersion {
+            year: hex(&date[0..2])?,
+            month: hex(&date[2..4])?,
+            day: hex(&date[4..6])?,
+            a: hex(parts.next().ok_or(Error::InvalidFWVersion)?)?,
+            b: hex(parts.next().ok_or(Error::InvalidFWVersion)?)?,
+            c: hex(parts.next().ok_or(Error::InvalidFWVersion)?)?,
+        };
+
+        if parts.next().is_some() {
+            return Err(Error::InvalidFWVersion);
+        }
+
+        Ok(version)
+    }
+}
+
 impl Display for FWVersion {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -82,14 +356,88 @@ impl Display for FWVersion {
 
 impl Device {
     pub fn new(backend: Box<dyn Backend>) -> Self {
-        Self { backend }
+        let model = backend.model();
+        Self::with_model(backend, model)
     }
 
-    pub fn read(&mut self, mut addr: u32, bfr: &mut [u8]) -> Result<(), Error> {
-        let mut cdb = [0_u8; 6];
+    /// Overrides the detected model, e.g. for chips that share USB IDs.
+    pub fn with_model(mut backend: Box<dyn Backend>, model: Model) -> Self {
+        let policy = TransferPolicy::default();
+        backend.set_policy(&policy);
+
+        Self {
+            backend,
+            model,
+            policy,
+        }
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub fn transport(&self) -> String {
+        self.backend.transport()
+    }
+
+    pub fn policy(&self) -> &TransferPolicy {
+        &self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: TransferPolicy) {
+        self.backend.set_policy(&policy);
+        self.policy = policy;
+    }
+
+    fn pause(duration: std::time::Duration) {
+        if !duration.is_zero() {
+            std::thread::sleep(duration);
+        }
+    }
+
+    /// Runs a single command and waits for the configured delay afterwards.
+    fn command<T>(
+        &mut self,
+        f: impl FnOnce(&mut dyn Backend) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let res = f(self.backend.as_mut());
+        Self::pause(self.policy.command_delay);
+        res
+    }
+
+    /// Like `command`, but repeats the command with exponential backoff as long as it
+    /// fails with a transient error. Only use this for commands without side effects.
+    fn command_retry<T>(
+        &mut self,
+        mut f: impl FnMut(&mut dyn Backend) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut backoff = self.policy.backoff;
+        let mut attempt = 0;
+
+        loop {
+            match self.command(&mut f) {
+                Err(err) if attempt < self.policy.retries && err.is_transient() => {
+                    attempt += 1;
+                    warn!("{}, retrying ({}/{})", err, attempt, self.policy.retries);
+                    Self::pause(backoff);
+                    backoff = backoff.saturating_mul(2);
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Fails unless `len` bytes at `addr` lie within XDATA.
+    pub fn check_xdata_range(addr: u32, len: usize) -> Result<(), Error> {
+        match (addr as usize).checked_add(len) {
+            Some(end) if end <= XDATA_SIZE as usize => Ok(()),
+            _ => Err(Error::InvalidAddress(addr, len)),
+        }
+    }
 
-        addr &= 0x01ffff;
-        addr |= 0x500000;
+    fn read_chunk(&mut self, addr: u32, bfr: &mut [u8]) -> Result<(), Error> {
+        let mut cdb = [0_u8; 6];
+        let addr = addr | 0x500000;
 
         cdb[0] = Command::Read as u8;
         cdb[1] = bfr.len() as u8;
@@ -98,14 +446,42 @@ impl Device {
         cdb[4] = addr as u8;
         cdb[5] = 0x00;
 
-        self.backend.transfer_from_device(&cdb, bfr)
+        // not retried, reading an MMIO register may have side effects
+        self.command(|backend| backend.transfer_from_device(&cdb, bfr))
     }
 
-    pub fn write(&mut self, mut addr: u32, value: u8) -> Result<(), Error> {
-        let mut cdb = [0_u8; 6];
+    pub fn read(&mut self, addr: u32, bfr: &mut [u8]) -> Result<(), Error> {
+        self.read_with_progress(addr, bfr, |_, _| {})
+    }
+
+    /// Reads an arbitrarily large XDATA range, split into as many Read commands as
+    /// necessary. `progress` is called with the number of bytes read so far and the total.
+    pub fn read_with_progress(
+        &mut self,
+        addr: u32,
+        bfr: &mut [u8],
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<(), Error> {
+        Self::check_xdata_range(addr, bfr.len())?;
+
+        let total = bfr.len();
+        let mut done = 0;
+
+        // the length field in the Read CDB is only a single byte
+        for chunk in bfr.chunks_mut(MAX_READ_CHUNK) {
+            self.read_chunk(addr + done as u32, chunk)?;
+            done += chunk.len();
+            progress(done, total);
+        }
+
+        Ok(())
+    }
+
+    pub fn write(&mut self, addr: u32, value: u8) -> Result<(), Error> {
+        Self::check_xdata_range(addr, 1)?;
 
-        addr &= 0x01ffff;
-        addr |= 0x500000;
+        let mut cdb = [0_u8; 6];
+        let addr = addr | 0x500000;
 
         cdb[0] = Command::Write as u8;
         cdb[1] = value;
@@ -114,59 +490,113 @@ impl Device {
         cdb[4] = addr as u8;
         cdb[5] = 0x00;
 
-        self.backend.transfer(&cdb)
+        self.command(|backend| backend.transfer(&cdb))
+    }
+
+    /// Soft resets the chip which will then disconnect and re-enumerate.
+    /// The device can no longer be used afterwards and has to be looked up again.
+    pub fn reload(&mut self) -> Result<(), Error> {
+        let cdb = [Command::Reload as u8, 0x00, 0x00, 0x00, 0x00, 0x00];
+        self.command(|backend| backend.transfer_reset(&cdb))
     }
 
     pub fn read_fw_version(&mut self) -> Result<FWVersion, Error> {
         let mut bfr = [0_u8; 6];
         self.read(0x07f0, &mut bfr)?;
 
-        Ok(FWVersion {
-            year: bfr[0],
-            month: bfr[1],
-            day: bfr[2],
-            a: bfr[3],
-            b: bfr[4],
-            c: bfr[5],
-        })
+        Ok(FWVersion::from(bfr))
     }
 
     pub fn read_config(&mut self) -> Result<[u8; 0x80], Error> {
         let cdb = [Command::ConfigRead as u8, 0x50, 0x00, 0x00, 0x00, 0x00];
         let mut bfr = [0_u8; 0x80];
-        self.backend.transfer_from_device(&cdb, &mut bfr)?;
+        self.command_retry(|backend| backend.transfer_from_device(&cdb, &mut bfr))?;
         Ok(bfr)
     }
 
-    pub fn read_firmware(&mut self) -> Result<Vec<u8>, Error> {
-        let mut bfr = vec![0_u8; 0x17ee0];
+    pub fn write_config(&mut self, bfr: &[u8; 0x80]) -> Result<(), Error> {
+        let cdb = [Command::ConfigWrite as u8, 0x50, 0x00, 0x00, 0x00, 0x00];
+        self.command(|backend| backend.transfer_to_device(&cdb, bfr))?;
 
-        let mut cdb = [Command::FlashRead as u8, 0x00, 0x00, 0x00, 0x00, 0x00];
+        // read the configuration back to make sure the write actually landed
+        if self.read_config()? != *bfr {
+            return Err(Error::ConfigVerifyFailed);
+        }
 
-        // first part, 0x0 to 0xff00
-        cdb[1] = 0x50;
-        cdb[2] = 0x00;
-        cdb[3] = 0x00;
-        cdb[4] = 0xff;
-        cdb[5] = 0x00;
-        self.backend
-            .transfer_from_device(&cdb, &mut bfr[..0xff00])?;
+        Ok(())
+    }
 
-        // the device sometimes dies if the next transfer is requested too quickly
-        std::thread::sleep(std::time::Duration::from_millis(1000));
+    pub fn read_firmware(&mut self) -> Result<Vec<u8>, Error> {
+        let info = self.model.info();
+        let mut bfr = vec![0_u8; info.flash_size];
 
-        // second part, 0xff00 - 0x17ee0
-        cdb[1] = 0xd0;
-        cdb[2] = 0x00;
-        cdb[3] = 0x00;
-        cdb[4] = 0x7f;
-        cdb[5] = 0xe0;
-        self.backend
-            .transfer_from_device(&cdb, &mut bfr[0xff00..])?;
+        for region in info.firmware_regions {
+            let len = (region.range.len() as u32).to_be_bytes();
+            let cdb = [
+                Command::FlashRead as u8,
+                region.selector,
+                len[0],
+                len[1],
+                len[2],
+                len[3],
+            ];
+            self.command_retry(|backend| {
+                backend.transfer_from_device(&cdb, &mut bfr[region.range.clone()])
+            })?;
 
-        // the device sometimes dies if the next transfer is requested too quickly
-        std::thread::sleep(std::time::Duration::from_millis(1000));
+            Self::pause(self.policy.flash_delay);
+        }
 
         Ok(bfr)
     }
+
+    pub fn write_firmware(&mut self, bfr: &[u8]) -> Result<(), Error> {
+        let info = self.model.info();
+        if bfr.len() != info.flash_size {
+            return Err(Error::InvalidFirmwareSize(bfr.len()));
+        }
+
+        for region in info.firmware_regions {
+            let len = (region.range.len() as u32).to_be_bytes();
+            let cdb = [
+                Command::FlashWrite as u8,
+                region.selector,
+                len[0],
+                len[1],
+                len[2],
+                len[3],
+            ];
+            self.command(|backend| backend.transfer_to_device(&cdb, &bfr[region.range.clone()]))?;
+
+            Self::pause(self.policy.flash_delay);
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs `f` on every device at once, each on a thread of its own, so the slow and sleep
+/// heavy transfers of different devices overlap. `f` gets the index of the device and
+/// the results are returned in the order of `devices`.
+pub fn run_parallel<T: Send>(
+    devices: &mut [Device],
+    f: impl Fn(usize, &mut Device) -> T + Sync,
+) -> Vec<T> {
+    let f = &f;
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = devices
+            .iter_mut()
+            .enumerate()
+            .map(|(i, device)| scope.spawn(move || f(i, device)))
+            .collect();
+
+        workers
+            .into_iter()
+            .map(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+            })
+            .collect()
+    })
 }
@@ -0,0 +1,149 @@
+/*
+ *  asm2x6xtool - configuration and firmware management for ASM2x6x chips
+ *  Copyright (C) 2024 Sven Peter <sven@svenpeter.dev>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::asm2x6x::Model;
+use crate::error::Error;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+
+pub const CONFIG_SIZE: usize = 0x80;
+
+/// Offsets of the decoded fields inside the configuration block.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ConfigLayout {
+    pub vid: usize,
+    pub pid: usize,
+    pub power_flags: usize,
+    pub port_flags: usize,
+    pub vendor: Range<usize>,
+    pub product: Range<usize>,
+    pub serial: Range<usize>,
+    /// whether the offsets were checked against a documented or known-good block;
+    /// fields decoded with an unverified layout are reported as such
+    pub verified: bool,
+}
+
+pub const ASM246X_LAYOUT: ConfigLayout = ConfigLayout {
+    vid: 0x00,
+    pid: 0x02,
+    power_flags: 0x08,
+    port_flags: 0x09,
+    vendor: 0x10..0x30,
+    product: 0x30..0x50,
+    serial: 0x50..0x70,
+    // best guess without a source, none of these offsets have been confirmed against a
+    // known block. No checksum has been confirmed either, so the last byte is kept as is.
+    verified: false,
+};
+
+/// Decoded view of the 0x80 byte configuration block.
+///
+/// Only the fields that are commonly changed are decoded; everything else is kept
+/// in the raw block so that converting back to bytes is lossless.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub vid: u16,
+    pub pid: u16,
+    pub power_flags: u8,
+    pub port_flags: u8,
+    pub vendor: String,
+    pub product: String,
+    pub serial: String,
+    layout: &'static ConfigLayout,
+    raw: [u8; CONFIG_SIZE],
+}
+
+fn decode_string(bfr: &[u8]) -> String {
+    let len = bfr.iter().position(|&c| c == 0).unwrap_or(bfr.len());
+    String::from_utf8_lossy(&bfr[..len]).into_owned()
+}
+
+fn encode_string(bfr: &mut [u8], value: &str, field: &'static str) -> Result<(), Error> {
+    // strings that were not changed are left alone to keep any padding bytes intact
+    if decode_string(bfr) == value {
+        return Ok(());
+    }
+
+    if value.len() > bfr.len() || !value.is_ascii() || value.contains('\0') {
+        return Err(Error::InvalidConfigField(field));
+    }
+
+    bfr.fill(0);
+    bfr[..value.len()].copy_from_slice(value.as_bytes());
+    Ok(())
+}
+
+impl Config {
+    pub fn new(bfr: &[u8; CONFIG_SIZE], model: Model) -> Self {
+        let layout = model.info().config_layout;
+
+        Config {
+            vid: u16::from_le_bytes([bfr[layout.vid], bfr[layout.vid + 1]]),
+            pid: u16::from_le_bytes([bfr[layout.pid], bfr[layout.pid + 1]]),
+            power_flags: bfr[layout.power_flags],
+            port_flags: bfr[layout.port_flags],
+            vendor: decode_string(&bfr[layout.vendor.clone()]),
+            product: decode_string(&bfr[layout.product.clone()]),
+            serial: decode_string(&bfr[layout.serial.clone()]),
+            layout,
+            raw: *bfr,
+        }
+    }
+
+    pub fn parse(bfr: &[u8], model: Model) -> Result<Self, Error> {
+        let bfr =
+            <&[u8; CONFIG_SIZE]>::try_from(bfr).map_err(|_| Error::InvalidConfigSize(bfr.len()))?;
+        Ok(Config::new(bfr, model))
+    }
+
+    /// False if the fields were decoded with a layout that is not verified and may be
+    /// wrong.
+    pub fn layout_verified(&self) -> bool {
+        self.layout.verified
+    }
+
+    /// Encodes all fields back into the configuration block they were read from. Fields
+    /// that were not changed keep their exact bytes.
+    pub fn to_bytes(&self) -> Result<[u8; CONFIG_SIZE], Error> {
+        let layout = self.layout;
+        let mut bfr = self.raw;
+
+        bfr[layout.vid..layout.vid + 2].copy_from_slice(&self.vid.to_le_bytes());
+        bfr[layout.pid..layout.pid + 2].copy_from_slice(&self.pid.to_le_bytes());
+        bfr[layout.power_flags] = self.power_flags;
+        bfr[layout.port_flags] = self.port_flags;
+        encode_string(&mut bfr[layout.vendor.clone()], &self.vendor, "vendor")?;
+        encode_string(&mut bfr[layout.product.clone()], &self.product, "product")?;
+        encode_string(&mut bfr[layout.serial.clone()], &self.serial, "serial")?;
+
+        Ok(bfr)
+    }
+}
+
+impl Display for Config {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if !self.layout.verified {
+            writeln!(f, "layout:      unverified, the fields below may be wrong")?;
+        }
+        writeln!(f, "USB ID:      {:04x}:{:04x}", self.vid, self.pid)?;
+        writeln!(f, "vendor:      {:?}", self.vendor)?;
+        writeln!(f, "product:     {:?}", self.product)?;
+        writeln!(f, "serial:      {:?}", self.serial)?;
+        writeln!(f, "power flags: {:#04x}", self.power_flags)?;
+        write!(f, "port flags:  {:#04x}", self.port_flags)
+    }
+}
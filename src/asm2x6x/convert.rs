@@ -0,0 +1,204 @@
+/*
+ *  asm2x6xtool - configuration and firmware management for ASM2x6x chips
+ *  Copyright (C) 2024 Sven Peter <sven@svenpeter.dev>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Conversion of firmware images between file formats.
+//!
+//! The image itself is never modified, a conversion only changes how the flash contents
+//! are stored. The ASMedia updater files are not supported since their format is not
+//! known.
+
+use crate::asm2x6x::firmware::Firmware;
+use crate::asm2x6x::Model;
+use crate::error::Error;
+use std::fmt::{Display, Formatter, Write};
+use std::str::FromStr;
+
+const HEX_RECORD_SIZE: usize = 0x10;
+
+const HEX_DATA: u8 = 0x00;
+const HEX_END_OF_FILE: u8 = 0x01;
+const HEX_EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const HEX_START_SEGMENT_ADDRESS: u8 = 0x03;
+const HEX_EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const HEX_START_LINEAR_ADDRESS: u8 = 0x05;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// flash contents as written by read-firmware
+    Raw,
+    /// Intel HEX with addresses relative to the start of flash
+    IntelHex,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "raw" | "bin" => Ok(Format::Raw),
+            "hex" | "ihex" => Ok(Format::IntelHex),
+            _ => Err(Error::UnknownFormat),
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Format::Raw => write!(f, "raw"),
+            Format::IntelHex => write!(f, "hex"),
+        }
+    }
+}
+
+impl Format {
+    /// Guesses the format from the file contents.
+    pub fn detect(data: &[u8]) -> Self {
+        if data.first() == Some(&b':') && data.is_ascii() {
+            Format::IntelHex
+        } else {
+            Format::Raw
+        }
+    }
+}
+
+fn hex_record(out: &mut String, record_type: u8, addr: u16, data: &[u8]) {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&addr.to_be_bytes());
+    bytes.push(record_type);
+    bytes.extend_from_slice(data);
+
+    let checksum = bytes
+        .iter()
+        .fold(0_u8, |sum, &b| sum.wrapping_add(b))
+        .wrapping_neg();
+    bytes.push(checksum);
+
+    out.push(':');
+    for b in bytes {
+        write!(out, "{:02X}", b).expect("writing to a String never fails");
+    }
+    out.push('\n');
+}
+
+/// Writes the image as Intel HEX. Records never cross a flash region or a 64 KiB
+/// boundary. The image has to fill the flash of its model exactly.
+pub fn write_hex(firmware: &Firmware) -> Result<String, Error> {
+    let len = firmware.as_bytes().len();
+    if len != firmware.model().info().flash_size {
+        return Err(Error::InvalidFirmwareSize(len));
+    }
+
+    let mut out = String::new();
+    let mut upper = None;
+
+    for region in firmware.model().info().firmware_regions {
+        let mut addr = region.range.start;
+
+        while addr < region.range.end {
+            if upper != Some(addr >> 16) {
+                upper = Some(addr >> 16);
+                hex_record(
+                    &mut out,
+                    HEX_EXTENDED_LINEAR_ADDRESS,
+                    0,
+                    &((addr >> 16) as u16).to_be_bytes(),
+                );
+            }
+
+            let end = (addr + HEX_RECORD_SIZE)
+                .min(region.range.end)
+                .min((addr | 0xffff) + 1);
+            hex_record(
+                &mut out,
+                HEX_DATA,
+                addr as u16,
+                &firmware.as_bytes()[addr..end],
+            );
+            addr = end;
+        }
+    }
+
+    hex_record(&mut out, HEX_END_OF_FILE, 0, &[]);
+    Ok(out)
+}
+
+fn parse_hex_line(line: &str) -> Option<(u8, u16, Vec<u8>)> {
+    let digits = line.strip_prefix(':')?;
+    if digits.len() % 2 != 0 || !digits.is_ascii() {
+        return None;
+    }
+
+    let bytes = (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    let (len, rest) = bytes.split_first()?;
+    if rest.len() != *len as usize + 4 || bytes.iter().fold(0_u8, |s, &b| s.wrapping_add(b)) != 0 {
+        return None;
+    }
+
+    Some((
+        rest[2],
+        u16::from_be_bytes([rest[0], rest[1]]),
+        rest[3..3 + *len as usize].to_vec(),
+    ))
+}
+
+/// Reads an Intel HEX file that has to cover the whole firmware area of `model`.
+pub fn read_hex(text: &str, model: Model) -> Result<Firmware, Error> {
+    let size = model.info().flash_size;
+    let mut data = vec![0_u8; size];
+    let mut present = vec![false; size];
+    let mut base = 0_usize;
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (record_type, addr, bytes) = parse_hex_line(line).ok_or(Error::InvalidHex(i + 1))?;
+        match record_type {
+            HEX_DATA => {
+                let start = base + addr as usize;
+                let end = start + bytes.len();
+                if end > size {
+                    return Err(Error::InvalidHex(i + 1));
+                }
+                data[start..end].copy_from_slice(&bytes);
+                present[start..end].fill(true);
+            }
+            HEX_END_OF_FILE => break,
+            HEX_EXTENDED_SEGMENT_ADDRESS if bytes.len() == 2 => {
+                base = (u16::from_be_bytes([bytes[0], bytes[1]]) as usize) << 4;
+            }
+            HEX_EXTENDED_LINEAR_ADDRESS if bytes.len() == 2 => {
+                base = (u16::from_be_bytes([bytes[0], bytes[1]]) as usize) << 16;
+            }
+            HEX_START_SEGMENT_ADDRESS | HEX_START_LINEAR_ADDRESS => (),
+            _ => return Err(Error::InvalidHex(i + 1)),
+        }
+    }
+
+    if let Some(missing) = present.iter().position(|present| !present) {
+        return Err(Error::MissingFirmwareData(missing));
+    }
+
+    Ok(Firmware::new(data, model))
+}
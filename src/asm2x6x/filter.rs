@@ -0,0 +1,77 @@
+/*
+ *  asm2x6xtool - configuration and firmware management for ASM2x6x chips
+ *  Copyright (C) 2024 Sven Peter <sven@svenpeter.dev>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::asm2x6x::config::Config;
+use crate::asm2x6x::{Device, DeviceDetails, FWVersion, Info, Model};
+use crate::error::Error;
+use log::warn;
+
+/// Criteria for picking devices when several are connected. Unset fields match any
+/// device.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Filter {
+    pub model: Option<Model>,
+    pub firmware: Option<FWVersion>,
+    /// USB port path as in `DeviceDetails::port_path`, e.g. `2-1.3`
+    pub port_path: Option<String>,
+    /// USB serial number or, if that does not match, the serial number in the
+    /// configuration. The latter is only as reliable as the configuration layout.
+    pub serial: Option<String>,
+}
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        *self == Filter::default()
+    }
+
+    /// Checks the criteria that are known without opening the device.
+    pub fn matches_info(&self, info: &dyn Info) -> bool {
+        if self.model.is_some_and(|model| model != info.model()) {
+            return false;
+        }
+
+        match &self.port_path {
+            Some(port_path) => info.details().port_path.as_ref() == Some(port_path),
+            None => true,
+        }
+    }
+
+    /// Checks the criteria that have to be read from the device.
+    pub fn matches_device(
+        &self,
+        device: &mut Device,
+        details: &DeviceDetails,
+    ) -> Result<bool, Error> {
+        if let Some(firmware) = self.firmware {
+            if device.read_fw_version()? != firmware {
+                return Ok(false);
+            }
+        }
+
+        if let Some(serial) = &self.serial {
+            if details.serial.as_ref() != Some(serial) {
+                let config = Config::new(&device.read_config()?, device.model());
+                if !config.layout_verified() {
+                    warn!("matching the serial number from an unverified configuration layout");
+                }
+                return Ok(config.serial == *serial);
+            }
+        }
+
+        Ok(true)
+    }
+}
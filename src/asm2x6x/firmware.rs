@@ -0,0 +1,85 @@
+/*
+ *  asm2x6xtool - configuration and firmware management for ASM2x6x chips
+ *  Copyright (C) 2024 Sven Peter <sven@svenpeter.dev>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::asm2x6x::Model;
+use std::fmt::{Display, Formatter};
+
+/// Firmware image as read from or written to flash.
+///
+/// The layout of the image itself is not known, so nothing inside it is interpreted. Only
+/// properties that follow from the flash of the model are checked.
+pub struct Firmware {
+    model: Model,
+    data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    InvalidSize { expected: usize, found: usize },
+    Erased,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::InvalidSize { expected, found } => write!(
+                f,
+                "image has {:#x} bytes but the flash holds {:#x}",
+                found, expected
+            ),
+            Problem::Erased => write!(f, "image is erased flash, every byte is 0xff"),
+        }
+    }
+}
+
+impl Firmware {
+    pub fn new(data: Vec<u8>, model: Model) -> Self {
+        Firmware { model, data }
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Returns everything that looks wrong with the image. An empty list means
+    /// the image is safe to flash as far as we can tell.
+    pub fn validate(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+
+        let expected = self.model.info().flash_size;
+        if self.data.len() != expected {
+            problems.push(Problem::InvalidSize {
+                expected,
+                found: self.data.len(),
+            });
+        }
+
+        if self.data.iter().all(|&b| b == 0xff) {
+            problems.push(Problem::Erased);
+        }
+
+        problems
+    }
+}
@@ -0,0 +1,102 @@
+/*
+ *  asm2x6xtool - configuration and firmware management for ASM2x6x chips
+ *  Copyright (C) 2024 Sven Peter <sven@svenpeter.dev>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::asm2x6x::Command;
+use std::time::Duration;
+
+/// Groups of commands that need similar timeouts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandClass {
+    /// Read and Write of XDATA
+    Register,
+    Config,
+    Flash,
+    Reset,
+    /// anything that is not one of the vendor commands
+    Other,
+}
+
+impl CommandClass {
+    pub fn from_cdb(cdb: &[u8]) -> Self {
+        match cdb
+            .first()
+            .and_then(|&opcode| Command::try_from(opcode).ok())
+        {
+            Some(Command::Read | Command::Write) => CommandClass::Register,
+            Some(Command::ConfigRead | Command::ConfigWrite) => CommandClass::Config,
+            Some(Command::FlashRead | Command::FlashWrite) => CommandClass::Flash,
+            Some(Command::Reload) => CommandClass::Reset,
+            None => CommandClass::Other,
+        }
+    }
+}
+
+/// Timeouts, retries and delays for talking to a device.
+///
+/// Backends only use the timeouts; retries and delays are handled by `Device`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferPolicy {
+    pub register_timeout: Duration,
+    pub config_timeout: Duration,
+    /// erasing and programming a whole region takes several seconds
+    pub flash_timeout: Duration,
+    pub reset_timeout: Duration,
+    /// how often a failed configuration or flash read is repeated; XDATA reads are not
+    /// retried since reading an MMIO register may have side effects, writes never are
+    pub retries: u32,
+    /// wait before the first retry, doubled for every further one
+    pub backoff: Duration,
+    /// pause after every command
+    pub command_delay: Duration,
+    /// pause after each flash region since the device sometimes dies if the next
+    /// transfer is requested too quickly
+    pub flash_delay: Duration,
+    /// wait after a reload before looking for the device again, repeated while it is
+    /// still missing
+    pub reconnect_delay: Duration,
+}
+
+impl Default for TransferPolicy {
+    fn default() -> Self {
+        TransferPolicy {
+            register_timeout: Duration::from_secs(1),
+            config_timeout: Duration::from_secs(2),
+            flash_timeout: Duration::from_secs(30),
+            reset_timeout: Duration::from_secs(1),
+            retries: 2,
+            backoff: Duration::from_millis(100),
+            command_delay: Duration::ZERO,
+            flash_delay: Duration::from_millis(1000),
+            reconnect_delay: Duration::from_secs(2),
+        }
+    }
+}
+
+impl TransferPolicy {
+    pub fn timeout(&self, class: CommandClass) -> Duration {
+        match class {
+            CommandClass::Register | CommandClass::Other => self.register_timeout,
+            CommandClass::Config => self.config_timeout,
+            CommandClass::Flash => self.flash_timeout,
+            CommandClass::Reset => self.reset_timeout,
+        }
+    }
+
+    pub fn timeout_for(&self, cdb: &[u8]) -> Duration {
+        self.timeout(CommandClass::from_cdb(cdb))
+    }
+}
@@ -0,0 +1,150 @@
+/*
+ *  asm2x6xtool - configuration and firmware management for ASM2x6x chips
+ *  Copyright (C) 2024 Sven Peter <sven@svenpeter.dev>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::asm2x6x::{Device, FWVersion, Model, XDATA_SIZE};
+use crate::error::Error;
+use std::io::{BufRead, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MAGIC: &str = "asm2x6x-snapshot 1";
+
+/// Copy of the whole XDATA address space together with what it was taken from.
+///
+/// Stored as a short text header terminated by an empty line, followed by the raw bytes.
+pub struct Snapshot {
+    pub model: Model,
+    pub version: FWVersion,
+    /// seconds since the unix epoch
+    pub timestamp: u64,
+    pub data: Vec<u8>,
+}
+
+/// A run of consecutive bytes that differ between two snapshots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub addr: u32,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+}
+
+impl Snapshot {
+    pub fn capture(device: &mut Device, progress: impl FnMut(usize, usize)) -> Result<Self, Error> {
+        let version = device.read_fw_version()?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let mut data = vec![0_u8; XDATA_SIZE as usize];
+        device.read_with_progress(0, &mut data, progress)?;
+
+        Ok(Snapshot {
+            model: device.model(),
+            version,
+            timestamp,
+            data,
+        })
+    }
+
+    pub fn write_to(&self, mut w: impl Write) -> Result<(), Error> {
+        writeln!(w, "{}", MAGIC)?;
+        writeln!(w, "model: {}", self.model)?;
+        writeln!(w, "firmware: {}", self.version)?;
+        writeln!(w, "timestamp: {}", self.timestamp)?;
+        writeln!(w, "size: {}", self.data.len())?;
+        writeln!(w)?;
+        w.write_all(&self.data)?;
+        Ok(())
+    }
+
+    pub fn read_from(r: impl Read) -> Result<Self, Error> {
+        let mut r = std::io::BufReader::new(r);
+        let mut line = String::new();
+
+        r.read_line(&mut line)?;
+        if line.trim_end() != MAGIC {
+            return Err(Error::InvalidSnapshot);
+        }
+
+        let mut model = None;
+        let mut version = None;
+        let mut timestamp = None;
+        let mut size = None;
+
+        loop {
+            line.clear();
+            if r.read_line(&mut line)? == 0 {
+                return Err(Error::InvalidSnapshot);
+            }
+
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+
+            let (key, value) = line.split_once(": ").ok_or(Error::InvalidSnapshot)?;
+            match key {
+                "model" => model = value.parse().ok(),
+                "firmware" => version = value.parse().ok(),
+                "timestamp" => timestamp = value.parse().ok(),
+                "size" => size = value.parse::<usize>().ok(),
+                // unknown keys are ignored so that newer snapshots can still be read
+                _ => (),
+            }
+        }
+
+        // the size comes from the file, do not let it pick how much memory is allocated
+        let size = size
+            .filter(|&size| size <= XDATA_SIZE as usize)
+            .ok_or(Error::InvalidSnapshot)?;
+        let mut data = vec![0_u8; size];
+        r.read_exact(&mut data)?;
+
+        Ok(Snapshot {
+            model: model.ok_or(Error::InvalidSnapshot)?,
+            version: version.ok_or(Error::InvalidSnapshot)?,
+            timestamp: timestamp.ok_or(Error::InvalidSnapshot)?,
+            data,
+        })
+    }
+
+    /// Lists all ranges that differ between `self` and `other`. Only the common
+    /// prefix is compared if the snapshots have different sizes.
+    pub fn diff(&self, other: &Snapshot) -> Vec<Change> {
+        let mut changes: Vec<Change> = Vec::new();
+
+        for (addr, (&old, &new)) in self.data.iter().zip(other.data.iter()).enumerate() {
+            if old == new {
+                continue;
+            }
+
+            match changes.last_mut() {
+                Some(change) if change.addr as usize + change.old.len() == addr => {
+                    change.old.push(old);
+                    change.new.push(new);
+                }
+                _ => changes.push(Change {
+                    addr: addr as u32,
+                    old: vec![old],
+                    new: vec![new],
+                }),
+            }
+        }
+
+        changes
+    }
+}
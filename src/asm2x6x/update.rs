@@ -0,0 +1,175 @@
+/*
+ *  asm2x6xtool - configuration and firmware management for ASM2x6x chips
+ *  Copyright (C) 2024 Sven Peter <sven@svenpeter.dev>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Firmware updates that back up the old firmware, verify the new one and go back to
+//! the old firmware if anything fails after flashing started.
+
+use crate::asm2x6x::config::CONFIG_SIZE;
+use crate::asm2x6x::firmware::Firmware;
+use crate::asm2x6x::policy::TransferPolicy;
+use crate::asm2x6x::{Device, FWVersion};
+use crate::error::Error;
+use log::{debug, error, info};
+
+// how often the device is looked for after a reload, reconnect_delay apart
+const RECONNECT_ATTEMPTS: usize = 10;
+
+/// Contents of the device before the update.
+pub struct Backup {
+    pub firmware: Vec<u8>,
+    pub config: [u8; CONFIG_SIZE],
+}
+
+/// Writes `bfr` and reads it back.
+fn flash(device: &mut Device, bfr: &[u8]) -> Result<(), Error> {
+    info!("writing firmware");
+    device.write_firmware(bfr)?;
+
+    info!("verifying firmware");
+    if device.read_firmware()? != bfr {
+        return Err(Error::FirmwareVerifyFailed);
+    }
+
+    Ok(())
+}
+
+fn reconnect(
+    policy: &TransferPolicy,
+    reopen: &mut impl FnMut() -> Result<Device, Error>,
+) -> Result<Device, Error> {
+    let mut attempt = 1;
+
+    loop {
+        std::thread::sleep(policy.reconnect_delay);
+
+        match reopen() {
+            Ok(mut device) => {
+                device.set_policy(*policy);
+                return Ok(device);
+            }
+            Err(err) if attempt < RECONNECT_ATTEMPTS => {
+                debug!("device not back yet: {}", err);
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn restart(
+    mut device: Device,
+    policy: &TransferPolicy,
+    reopen: &mut impl FnMut() -> Result<Device, Error>,
+) -> Result<Device, Error> {
+    info!("reloading device");
+    device.reload()?;
+    drop(device);
+
+    reconnect(policy, reopen)
+}
+
+/// Flashes the backup again. `device` is None if the device did not come back after
+/// the update.
+fn rollback(
+    device: Option<Device>,
+    backup: &Backup,
+    policy: &TransferPolicy,
+    reopen: &mut impl FnMut() -> Result<Device, Error>,
+) -> Result<Device, Error> {
+    let mut device = match device {
+        Some(device) => device,
+        None => reconnect(policy, reopen)?,
+    };
+
+    flash(&mut device, &backup.firmware)?;
+    restart(device, policy, reopen)
+}
+
+/// Updates the firmware of `device` to `image`:
+///
+/// 1. the image is validated against the model of the device
+/// 2. the current firmware and configuration are read and passed to `save`, which has
+///    to store them before anything is written
+/// 3. the image is written and read back
+/// 4. the device is reloaded and found again with `reopen`
+/// 5. if `expected` is given, the firmware version the device reports is compared to it
+///
+/// The version is not taken from the image since its location in flash is not known.
+///
+/// If any step after the first write fails the backup is flashed again and
+/// `Error::UpdateRolledBack` is returned. On success the re-opened device is returned.
+pub fn update_firmware(
+    mut device: Device,
+    image: &Firmware,
+    expected: Option<FWVersion>,
+    save: impl FnOnce(&Backup) -> Result<(), Error>,
+    mut reopen: impl FnMut() -> Result<Device, Error>,
+) -> Result<Device, Error> {
+    if image.model() != device.model() {
+        error!(
+            "image is for {} but the device is {}",
+            image.model(),
+            device.model()
+        );
+        return Err(Error::InvalidFirmware);
+    }
+
+    let problems = image.validate();
+    for problem in problems.iter() {
+        error!("{}", problem);
+    }
+    if !problems.is_empty() {
+        return Err(Error::InvalidFirmware);
+    }
+
+    info!("backing up firmware and configuration");
+    let backup = Backup {
+        firmware: device.read_firmware()?,
+        config: device.read_config()?,
+    };
+    save(&backup)?;
+
+    let policy = *device.policy();
+
+    let (err, device) = match flash(&mut device, image.as_bytes()) {
+        Err(err) => (err, Some(device)),
+        Ok(()) => match restart(device, &policy, &mut reopen) {
+            Err(err) => (err, None),
+            Ok(mut device) => match (device.read_fw_version(), expected) {
+                (Ok(found), Some(expected)) if found != expected => {
+                    (Error::UnexpectedFWVersion { expected, found }, Some(device))
+                }
+                (Ok(found), _) => {
+                    info!("device runs firmware {}", found);
+                    return Ok(device);
+                }
+                (Err(err), _) => (err, Some(device)),
+            },
+        },
+    };
+
+    error!("firmware update failed: {}", err);
+    info!("restoring previous firmware");
+
+    match rollback(device, &backup, &policy, &mut reopen) {
+        Ok(_) => Err(Error::UpdateRolledBack(Box::new(err))),
+        Err(rollback) => Err(Error::RollbackFailed {
+            update: Box::new(err),
+            rollback: Box::new(rollback),
+        }),
+    }
+}
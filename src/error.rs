@@ -15,27 +15,107 @@
  *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use crate::asm2x6x::FWVersion;
+use crate::scsi::{Sense, SenseKey};
 use std::fmt::{Display, Formatter};
 
 #[derive(Debug)]
 pub enum Error {
     USB(rusb::Error),
+    NoMassStorageInterface,
     InvalidCDB,
     InvalidCSW,
     CSWIOError(u8),
+    CSWPhaseError,
     TransferStillPending,
     InvalidCSWTag,
     NoTransferPending,
     CSWResidue(u32),
+    ShortTransfer(usize),
+    Sense(Sense),
+    InvalidUASTag,
+    InvalidUASIU(u8),
+    UASStatus(u8),
+    UASResponse(u8),
     IO(std::io::Error),
+    InvalidFirmwareSize(usize),
+    InvalidFirmware,
+    InvalidConfigSize(usize),
+    ConfigVerifyFailed,
+    FirmwareVerifyFailed,
+    UnexpectedFWVersion {
+        expected: FWVersion,
+        found: FWVersion,
+    },
+    /// the update failed, the device runs the previous firmware again
+    UpdateRolledBack(Box<Error>),
+    RollbackFailed {
+        update: Box<Error>,
+        rollback: Box<Error>,
+    },
+    DeviceNotFound,
+    UnknownFormat,
+    InvalidHex(usize),
+    MissingFirmwareData(usize),
+    InvalidConfigField(&'static str),
+    UnknownModel,
+    InvalidAddress(u32, usize),
+    InvalidFWVersion,
+    InvalidSnapshot,
+    InvalidTrace(usize),
+    TraceMismatch(usize),
+    Replayed {
+        message: String,
+        transient: bool,
+    },
     #[cfg(target_os = "linux")]
     Nix(nix::Error),
     #[cfg(target_os = "linux")]
-    SgIoError,
+    UnsupportedDevicePath(std::path::PathBuf),
+    #[cfg(target_os = "linux")]
+    SgIoError {
+        status: u32,
+        host_status: u32,
+        driver_status: u32,
+    },
 }
 
 impl std::error::Error for Error {}
 
+impl Error {
+    /// Failures that may go away if the same command is simply sent again.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::USB(err) => matches!(
+                err,
+                rusb::Error::Timeout
+                    | rusb::Error::Pipe
+                    | rusb::Error::Io
+                    | rusb::Error::Overflow
+                    | rusb::Error::Interrupted
+            ),
+            Error::InvalidCSW
+            | Error::InvalidCSWTag
+            | Error::CSWPhaseError
+            | Error::ShortTransfer(_)
+            | Error::InvalidUASTag => true,
+            Error::Sense(sense) => sense.is_not_ready() || sense.key == SenseKey::UnitAttention,
+            #[cfg(target_os = "linux")]
+            Error::Nix(errno) => matches!(
+                errno,
+                nix::errno::Errno::EIO
+                    | nix::errno::Errno::ETIMEDOUT
+                    | nix::errno::Errno::EAGAIN
+                    | nix::errno::Errno::EBUSY
+            ),
+            #[cfg(target_os = "linux")]
+            Error::SgIoError { .. } => true,
+            Error::Replayed { transient, .. } => *transient,
+            _ => false,
+        }
+    }
+}
+
 impl From<rusb::Error> for Error {
     fn from(err: rusb::Error) -> Self {
         Error::USB(err)
@@ -52,18 +132,84 @@ impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::USB(err) => write!(f, "USB error: {}", err),
+            Error::NoMassStorageInterface => write!(f, "No usable mass storage interface found"),
             Error::InvalidCDB => write!(f, "Invalid arguments to create CDW"),
             Error::InvalidCSW => write!(f, "Invalid CSW signature"),
             Error::CSWIOError(io) => write!(f, "CSW I/O error: {}", io),
+            Error::CSWPhaseError => write!(f, "CSW phase error"),
             Error::TransferStillPending => write!(f, "Transfer still pending"),
             Error::InvalidCSWTag => write!(f, "Invalid CSW tag"),
             Error::NoTransferPending => write!(f, "No transfer pending"),
             Error::CSWResidue(residue) => write!(f, "CSW residue > 0: {}", residue),
+            Error::ShortTransfer(residue) => {
+                write!(f, "Short transfer, {} bytes missing", residue)
+            }
+            Error::Sense(sense) => write!(f, "SCSI check condition: {}", sense),
+            Error::InvalidUASTag => write!(f, "Invalid UAS IU tag"),
+            Error::InvalidUASIU(id) => write!(f, "Unexpected UAS IU: {:#04x}", id),
+            Error::UASStatus(status) => write!(f, "UAS command failed with status {:#04x}", status),
+            Error::UASResponse(code) => write!(f, "UAS response code {:#04x}", code),
             Error::IO(err) => write!(f, "IO error: {}", err),
+            Error::InvalidFirmwareSize(size) => {
+                write!(f, "Invalid firmware size: {:#x} bytes", size)
+            }
+            Error::InvalidFirmware => write!(f, "Firmware image failed validation"),
+            Error::InvalidConfigSize(size) => {
+                write!(f, "Invalid configuration size: {:#x} bytes", size)
+            }
+            Error::ConfigVerifyFailed => write!(f, "Configuration read back does not match"),
+            Error::FirmwareVerifyFailed => write!(f, "Firmware read back does not match"),
+            Error::UnexpectedFWVersion { expected, found } => {
+                write!(f, "Device runs firmware {} instead of {}", found, expected)
+            }
+            Error::UpdateRolledBack(err) => {
+                write!(
+                    f,
+                    "Firmware update failed, previous firmware restored: {}",
+                    err
+                )
+            }
+            Error::RollbackFailed { update, rollback } => write!(
+                f,
+                "Firmware update failed ({}) and restoring the previous firmware failed too ({})",
+                update, rollback
+            ),
+            Error::DeviceNotFound => write!(f, "Device not found"),
+            Error::UnknownFormat => write!(f, "Unknown firmware file format"),
+            Error::InvalidHex(line) => write!(f, "Invalid Intel HEX record in line {}", line),
+            Error::MissingFirmwareData(offset) => {
+                write!(f, "Firmware image has no data at {:#07x}", offset)
+            }
+            Error::UnknownModel => write!(f, "Unknown model"),
+            Error::InvalidFWVersion => write!(f, "Invalid firmware version"),
+            Error::InvalidSnapshot => write!(f, "Invalid snapshot file"),
+            Error::InvalidTrace(line) => write!(f, "Invalid trace file in line {}", line),
+            Error::TraceMismatch(transfer) => {
+                write!(f, "Transfer {} does not match the trace", transfer)
+            }
+            Error::Replayed { message, .. } => write!(f, "Replayed error: {}", message),
+            Error::InvalidAddress(addr, len) => {
+                write!(f, "Invalid XDATA range: {:#x} bytes at {:#07x}", len, addr)
+            }
+            Error::InvalidConfigField(field) => {
+                write!(f, "Invalid value for configuration field {}", field)
+            }
             #[cfg(target_os = "linux")]
             Error::Nix(err) => write!(f, "Nix error: {}", err),
             #[cfg(target_os = "linux")]
-            Error::SgIoError => write!(f, "SG_IO ioctl failed"),
+            Error::UnsupportedDevicePath(path) => {
+                write!(f, "{} is not a SCSI device", path.display())
+            }
+            #[cfg(target_os = "linux")]
+            Error::SgIoError {
+                status,
+                host_status,
+                driver_status,
+            } => write!(
+                f,
+                "SG_IO ioctl failed: status {:#x}, host status {:#x}, driver status {:#x}",
+                status, host_status, driver_status
+            ),
         }
     }
 }
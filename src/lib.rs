@@ -17,6 +17,10 @@
 
 pub mod asm2x6x;
 pub mod error;
+pub mod json;
+pub mod scsi;
+pub mod sim;
+pub mod trace;
 pub mod usb;
 
 #[cfg(target_os = "linux")]
@@ -15,12 +15,16 @@
  *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use crate::asm2x6x::{Backend, Info, Model};
+use crate::asm2x6x::policy::TransferPolicy;
+use crate::asm2x6x::{Backend, DeviceDetails, Info, Model};
 use crate::error::Error;
+use crate::scsi::{Sense, SENSE_BUFFER_SIZE};
 use log::{debug, error};
 use nix::convert_ioctl_res;
+use nix::errno::Errno;
 use nix::libc::ioctl;
 use std::ffi::c_void;
+use std::fmt::{Display, Formatter};
 use std::fs;
 use std::os::fd::AsRawFd;
 use std::path::{Path, PathBuf};
@@ -33,18 +37,40 @@ mod sg {
     include!(concat!(env!("OUT_DIR"), "/sg.rs"));
 
     pub const SG_INTERFACE_ID_ORIG: i32 = 'S' as i32;
+    pub const SG_INTERFACE_ID_V4: i32 = 'Q' as i32;
 }
 
+/// Which flavour of the SG_IO ioctl the device node speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interface {
+    /// `sg_io_hdr` on /dev/sgN
+    SgV3,
+    /// `sg_io_v4` on /dev/bsg/H:C:T:L
+    BsgV4,
+    /// `sg_io_hdr` on /dev/sdX, for systems without the sg module
+    Block,
+}
+
+const SYSFS_SCSI_DEVICES: &str = "/sys/bus/scsi/devices";
+
+// host byte set by the SCSI midlayer when the device is gone, include/scsi/scsi_status.h
+const DID_NO_CONNECT: u32 = 0x01;
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DeviceInfo {
     pub path: String,
+    pub interface: Interface,
     pub model: Model,
+    /// /sys/bus/scsi/devices/H:C:T:L
+    pub sysfs: PathBuf,
+    pub details: DeviceDetails,
 }
 
 #[derive(Debug)]
 struct Device {
     info: DeviceInfo,
     fd: std::fs::File,
+    policy: TransferPolicy,
 }
 
 enum TransferBuffer<'a> {
@@ -53,62 +79,251 @@ enum TransferBuffer<'a> {
     FromDevice(&'a mut [u8]),
 }
 
+impl TransferBuffer<'_> {
+    fn len(&self) -> usize {
+        match self {
+            TransferBuffer::None => 0,
+            TransferBuffer::ToDevice(bfr) => bfr.len(),
+            TransferBuffer::FromDevice(bfr) => bfr.len(),
+        }
+    }
+
+    /// The kernel only ever reads from the buffer of a ToDevice transfer, so handing
+    /// it out as a mutable pointer is fine.
+    fn as_ptr(&mut self) -> *mut u8 {
+        match self {
+            TransferBuffer::None => std::ptr::null_mut(),
+            TransferBuffer::ToDevice(bfr) => bfr.as_ptr() as *mut u8,
+            TransferBuffer::FromDevice(bfr) => bfr.as_mut_ptr(),
+        }
+    }
+}
+
+/// Outcome of a SG_IO ioctl, independent of the interface version.
+#[derive(Debug)]
+struct Completion {
+    status: u32,
+    host_status: u32,
+    driver_status: u32,
+    /// number of bytes that were not transferred
+    resid: i32,
+    sense_len: usize,
+    /// milliseconds
+    duration: u32,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Devices(Vec<DeviceInfo>);
 
-fn file_starts_with(base: &PathBuf, fname: &str, start: &str) -> bool {
-    let path = base.as_path().join(fname);
+fn read_sysfs_string(base: &Path, fname: &str) -> Option<String> {
+    let path = base.join(fname);
     if !path.exists() {
-        return false;
+        return None;
     }
 
-    let contents = match fs::read_to_string(path.clone()) {
-        Ok(s) => s.trim().to_string(),
+    match fs::read_to_string(path.clone()) {
+        Ok(s) => {
+            let contents = s.trim().to_string();
+            debug!("{}: {}", fname, contents);
+            Some(contents)
+        }
         Err(e) => {
             debug!("failed to read {}: {}", path.display(), e);
-            return false;
+            None
         }
+    }
+}
+
+fn first_entry(dir: &Path, filter: impl Fn(&str) -> bool) -> Option<String> {
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(|dev| dev.ok())
+        .filter_map(|dev| dev.file_name().into_string().ok())
+        .find(|name| filter(name))
+}
+
+/// Finds the USB device a SCSI device belongs to by walking up the sysfs hierarchy,
+/// e.g. from .../usb2/2-1/2-1:1.0/host0/target0:0:0/0:0:0:0 to .../usb2/2-1.
+fn usb_parent(sysfs: &Path) -> Option<PathBuf> {
+    let sysfs = fs::canonicalize(sysfs).ok()?;
+
+    sysfs
+        .ancestors()
+        .find(|dir| dir.join("idVendor").exists() && dir.join("busnum").exists())
+        .map(Path::to_path_buf)
+}
+
+fn read_sysfs_hex(base: &Path, fname: &str) -> Option<u16> {
+    u16::from_str_radix(&read_sysfs_string(base, fname)?, 16).ok()
+}
+
+fn sysfs_details(sysfs: &Path) -> DeviceDetails {
+    let mut details = DeviceDetails {
+        revision: read_sysfs_string(sysfs, "rev"),
+        ..Default::default()
     };
 
-    debug!("{}: {}", fname, contents);
-    contents.starts_with(start)
+    let usb = match usb_parent(sysfs) {
+        Some(usb) => usb,
+        None => {
+            debug!("  {} is not a USB device", sysfs.display());
+            return details;
+        }
+    };
+    debug!("  USB device at {}", usb.display());
+
+    details.usb_id = read_sysfs_hex(&usb, "idVendor").zip(read_sysfs_hex(&usb, "idProduct"));
+    details.serial = read_sysfs_string(&usb, "serial");
+    details.speed = read_sysfs_string(&usb, "speed");
+    // the directory name is the bus number followed by the chain of hub ports
+    details.port_path = usb
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(str::to_string);
+
+    details
 }
 
-pub fn find_devices(devices: &mut Vec<Box<dyn Info>>) -> Result<(), Error> {
-    for path in fs::read_dir("/sys/bus/scsi/devices/")?
-        .into_iter()
-        .filter_map(|dev| dev.ok().and_then(|dev| Some(dev.path())))
+/// The USB ID tells models apart that share the same INQUIRY strings, so it is
+/// preferred if the device is attached via USB.
+fn detect_model(sysfs: &Path, details: &DeviceDetails) -> Option<Model> {
+    if let Some(model) = details
+        .usb_id
+        .and_then(|(vid, pid)| Model::from_usb_id(vid, pid))
     {
-        debug!("found scsi device candidate {:?}", path);
+        return Some(model);
+    }
 
-        if !file_starts_with(&path, "vendor", "ASMT") {
-            debug!("  vendor is not ASMedia");
-            continue;
+    let vendor = read_sysfs_string(sysfs, "vendor").unwrap_or_default();
+    let product = read_sysfs_string(sysfs, "model").unwrap_or_default();
+
+    let model = Model::from_inquiry(&vendor, &product);
+    if model.is_none() {
+        debug!("  {} {} is not a supported model", vendor, product);
+    }
+    model
+}
+
+fn accessible(path: &str) -> bool {
+    fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .is_ok()
+}
+
+impl DeviceInfo {
+    /// Picks the best device node of a SCSI device: bsg reports residuals for both
+    /// directions and is preferred if we may use it, then sg and finally the block
+    /// device if neither of the other drivers is loaded.
+    fn from_sysfs(sysfs: PathBuf, model: Model, details: DeviceDetails) -> Option<Self> {
+        let info = |path: String, interface| DeviceInfo {
+            path,
+            interface,
+            model,
+            sysfs: sysfs.clone(),
+            details: details.clone(),
+        };
+
+        if let Some(bsg) = first_entry(&sysfs.join("bsg"), |_| true) {
+            let path = format!("/dev/bsg/{}", bsg);
+            if accessible(&path) {
+                return Some(info(path, Interface::BsgV4));
+            }
+            debug!("  {} is not accessible", path);
         }
 
-        if !file_starts_with(&path, "model", "ASM246X") {
-            debug!("  model is not ASM246X");
-            continue;
+        if let Some(path) = generic_device(&sysfs) {
+            return Some(info(path, Interface::SgV3));
         }
 
-        let path_scsi_generic = path.as_path().join("scsi_generic");
-        if !path_scsi_generic.exists() {
-            debug!("  scsi_generic does not exist");
-            continue;
+        if let Some(path) = block_device(&sysfs) {
+            return Some(info(path, Interface::Block));
         }
 
-        for sg_x in fs::read_dir(path_scsi_generic)?
-            .into_iter()
-            .filter_map(|dev| dev.ok())
-            .filter_map(|dev| dev.file_name().into_string().ok())
-            .filter(|path| path.starts_with("sg"))
-        {
-            let path = format!("/dev/{}", sg_x);
-            let info = DeviceInfo {
-                path,
-                model: Model::ASM2464PD,
-            };
+        debug!("  no usable device node");
+        None
+    }
+
+    /// Looks up the SCSI device behind a device node or a symlink to one, e.g. from
+    /// /dev/disk/by-id or /dev/disk/by-path. Partitions resolve to their disk.
+    pub fn from_path(path: &Path) -> Result<Self, Error> {
+        let unsupported = || Error::UnsupportedDevicePath(path.to_path_buf());
+
+        let node = fs::canonicalize(path)?;
+        let mut name = node
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(unsupported)?
+            .to_string();
+
+        let (sysfs, interface) = if node.parent() == Some(Path::new("/dev/bsg")) {
+            (Path::new(SYSFS_SCSI_DEVICES).join(&name), Interface::BsgV4)
+        } else if Path::new("/sys/class/scsi_generic").join(&name).exists() {
+            (
+                Path::new("/sys/class/scsi_generic")
+                    .join(&name)
+                    .join("device"),
+                Interface::SgV3,
+            )
+        } else {
+            let mut block = Path::new("/sys/class/block").join(&name);
+            if block.join("partition").exists() {
+                // the parent of a partition in sysfs is the whole disk
+                block = fs::canonicalize(&block)?
+                    .parent()
+                    .ok_or_else(unsupported)?
+                    .to_path_buf();
+                name = block
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .ok_or_else(unsupported)?
+                    .to_string();
+            }
+            (block.join("device"), Interface::Block)
+        };
+
+        let sysfs = fs::canonicalize(sysfs).map_err(|_| unsupported())?;
+        let details = sysfs_details(&sysfs);
+        let model = detect_model(&sysfs, &details).ok_or(Error::UnknownModel)?;
+
+        let path = match interface {
+            Interface::BsgV4 => format!("/dev/bsg/{}", name),
+            Interface::SgV3 | Interface::Block => format!("/dev/{}", name),
+        };
+
+        Ok(DeviceInfo {
+            path,
+            interface,
+            model,
+            sysfs,
+            details,
+        })
+    }
+}
+
+/// Block device of a SCSI device, e.g. /dev/sda.
+fn block_device(sysfs: &Path) -> Option<String> {
+    first_entry(&sysfs.join("block"), |_| true).map(|name| format!("/dev/{}", name))
+}
+
+/// SCSI generic device of a SCSI device, e.g. /dev/sg2.
+fn generic_device(sysfs: &Path) -> Option<String> {
+    first_entry(&sysfs.join("scsi_generic"), |name| name.starts_with("sg"))
+        .map(|name| format!("/dev/{}", name))
+}
+
+pub fn find_devices(devices: &mut Vec<Box<dyn Info>>) -> Result<(), Error> {
+    for path in fs::read_dir(SYSFS_SCSI_DEVICES)?.filter_map(|dev| dev.ok().map(|dev| dev.path())) {
+        debug!("found scsi device candidate {:?}", path);
+
+        let details = sysfs_details(&path);
+        let model = match detect_model(&path, &details) {
+            Some(model) => model,
+            None => continue,
+        };
 
+        if let Some(info) = DeviceInfo::from_sysfs(path, model, details) {
             debug!("found device {:?}", info);
             devices.push(Box::new(info));
         }
@@ -117,92 +332,182 @@ pub fn find_devices(devices: &mut Vec<Box<dyn Info>>) -> Result<(), Error> {
     Ok(())
 }
 
-impl ToString for DeviceInfo {
-    fn to_string(&self) -> String {
-        format!("sg:{}", self.path)
+impl Display for DeviceInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.interface {
+            Interface::SgV3 => write!(f, "sg:{}", self.path),
+            Interface::BsgV4 => write!(f, "bsg:{}", self.path),
+            Interface::Block => write!(f, "sd:{}", self.path),
+        }
     }
 }
 
 impl Info for DeviceInfo {
     fn open(&self) -> Result<Box<dyn Backend>, Error> {
         let path = Path::new(&self.path);
-        let fd = std::fs::OpenOptions::new().read(true).open(path)?;
+        // bsg and the block layer only allow vendor commands on file descriptors
+        // opened for writing
+        let fd = std::fs::OpenOptions::new()
+            .read(true)
+            .write(self.interface != Interface::SgV3)
+            .open(path)?;
 
         Ok(Box::new(Device {
             info: self.clone(),
             fd,
+            policy: TransferPolicy::default(),
         }))
     }
 
     fn model(&self) -> Model {
         self.model
     }
+
+    fn details(&self) -> DeviceDetails {
+        self.details.clone()
+    }
 }
 
 impl Device {
-    fn ioctl_sg_io(&mut self, cdb: &[u8], xfer: TransferBuffer) -> Result<(), Error> {
-        let mut sgbuf: sg::sg_io_hdr = Default::default();
-        sgbuf.interface_id = sg::SG_INTERFACE_ID_ORIG;
+    fn ioctl<T>(&mut self, hdr: &mut T) -> Result<(), Error> {
+        // SAFETY:
+        // * sg::SG_IO is the correct ioctl number generated by bindgen
+        // * hdr is a valid sg_io_hdr or sg_io_v4 struct generated by bindgen, matching the
+        //   interface of the device node
+        // * the caller guarantees that all pointers inside hdr are valid for the lengths
+        //   stored next to them
+        unsafe { convert_ioctl_res!(ioctl(self.fd.as_raw_fd(), sg::SG_IO as u64, hdr)) }
+            .map_err(Error::Nix)?;
+        Ok(())
+    }
+
+    fn sg_io_v3(
+        &mut self,
+        cmd: &mut [u8],
+        xfer: &mut TransferBuffer,
+        sense_buffer: &mut [u8],
+    ) -> Result<Completion, Error> {
+        let mut sgbuf = sg::sg_io_hdr {
+            interface_id: sg::SG_INTERFACE_ID_ORIG,
+            timeout: self.policy.timeout_for(cmd).as_millis() as u32,
+            cmd_len: cmd.len() as u8,
+            cmdp: cmd.as_mut_ptr(),
+            sbp: sense_buffer.as_mut_ptr(),
+            mx_sb_len: sense_buffer.len() as u8,
+            dxfer_len: xfer.len() as u32,
+            dxferp: xfer.as_ptr() as *mut c_void,
+            dxfer_direction: match xfer {
+                TransferBuffer::None => sg::SG_DXFER_NONE,
+                TransferBuffer::ToDevice(_) => sg::SG_DXFER_TO_DEV,
+                TransferBuffer::FromDevice(_) => sg::SG_DXFER_FROM_DEV,
+            },
+            ..Default::default()
+        };
 
+        debug!("ioctl_sg_io (before): {:?}", sgbuf);
+        self.ioctl(&mut sgbuf)?;
+        debug!("ioctl_sg_io (after ): {:?}", sgbuf);
+
+        Ok(Completion {
+            status: sgbuf.status as u32,
+            host_status: sgbuf.host_status as u32,
+            driver_status: sgbuf.driver_status as u32,
+            resid: sgbuf.resid,
+            sense_len: sgbuf.sb_len_wr as usize,
+            duration: sgbuf.duration,
+        })
+    }
+
+    fn sg_io_v4(
+        &mut self,
+        cmd: &mut [u8],
+        xfer: &mut TransferBuffer,
+        sense_buffer: &mut [u8],
+    ) -> Result<Completion, Error> {
+        let mut hdr = sg::sg_io_v4 {
+            guard: sg::SG_INTERFACE_ID_V4,
+            protocol: sg::BSG_PROTOCOL_SCSI,
+            subprotocol: sg::BSG_SUB_PROTOCOL_SCSI_CMD,
+            request_len: cmd.len() as u32,
+            request: cmd.as_mut_ptr() as u64,
+            max_response_len: sense_buffer.len() as u32,
+            response: sense_buffer.as_mut_ptr() as u64,
+            timeout: self.policy.timeout_for(cmd).as_millis() as u32,
+            ..Default::default()
+        };
+
+        match xfer {
+            TransferBuffer::None => (),
+            TransferBuffer::ToDevice(_) => {
+                hdr.dout_xfer_len = xfer.len() as u32;
+                hdr.dout_xferp = xfer.as_ptr() as u64;
+            }
+            TransferBuffer::FromDevice(_) => {
+                hdr.din_xfer_len = xfer.len() as u32;
+                hdr.din_xferp = xfer.as_ptr() as u64;
+            }
+        }
+
+        debug!("ioctl_sg_io_v4 (before): {:?}", hdr);
+        self.ioctl(&mut hdr)?;
+        debug!("ioctl_sg_io_v4 (after ): {:?}", hdr);
+
+        Ok(Completion {
+            status: hdr.device_status,
+            host_status: hdr.transport_status,
+            driver_status: hdr.driver_status,
+            resid: match xfer {
+                TransferBuffer::ToDevice(_) => hdr.dout_resid,
+                _ => hdr.din_resid,
+            },
+            sense_len: hdr.response_len as usize,
+            duration: hdr.duration,
+        })
+    }
+
+    /// Transfers directly from and to `xfer` without an intermediate buffer.
+    fn ioctl_sg_io(&mut self, cdb: &[u8], mut xfer: TransferBuffer) -> Result<(), Error> {
         let mut cmd = [0_u8; 16];
         if cdb.len() > 16 {
             return Err(Error::InvalidCDB);
         }
         cmd[..cdb.len()].copy_from_slice(cdb);
-        sgbuf.cmd_len = cdb.len() as u8;
-        sgbuf.cmdp = cmd.as_mut_ptr();
-
-        let mut sense_buffer = [0_u8; 64];
-        sgbuf.sbp = sense_buffer.as_mut_ptr();
-        sgbuf.mx_sb_len = sense_buffer.len() as u8;
+        let cmd = &mut cmd[..cdb.len()];
 
-        let mut data = match xfer {
-            TransferBuffer::None => Vec::new(),
-            TransferBuffer::ToDevice(bfr) => Vec::from(bfr),
-            TransferBuffer::FromDevice(ref bfr) => vec![0_u8; bfr.len()],
-        };
-
-        sgbuf.dxferp = data.as_mut_ptr() as *mut c_void;
-        sgbuf.dxfer_len = data.len() as u32;
-        sgbuf.dxfer_direction = match xfer {
-            TransferBuffer::None => sg::SG_DXFER_NONE,
-            TransferBuffer::ToDevice(_) => sg::SG_DXFER_TO_DEV,
-            TransferBuffer::FromDevice(_) => sg::SG_DXFER_FROM_DEV,
-        };
+        let mut sense_buffer = [0_u8; SENSE_BUFFER_SIZE];
 
         debug!("cdb: {:?}", cmd);
-        debug!("ioctl_sg_io (before): {:?}", sgbuf);
-
-        // SAFETY:
-        // * sg::SG_IO is the correct ioctl number generated by bindgen
-        // * sgbuf is a valid pointer to a sg_io_hdr struct generated by bindgen
-        // * cmdp is a valid pointer to a 16-byte array and cmd_len is guaranteed to be <= 16
-        // * dxferep is a valid pointer to data and dxfer_len is set to its length; data is always mutable
-        // * sbp is a valid pointer to sense_buffer and mx_sb_len is set to its length
-        unsafe { convert_ioctl_res!(ioctl(self.fd.as_raw_fd(), sg::SG_IO as u64, &mut sgbuf)) }
-            .map_err(|e| Error::Nix(e))?;
+        let completion = match self.info.interface {
+            Interface::SgV3 | Interface::Block => {
+                self.sg_io_v3(cmd, &mut xfer, &mut sense_buffer)?
+            }
+            Interface::BsgV4 => self.sg_io_v4(cmd, &mut xfer, &mut sense_buffer)?,
+        };
+        debug!("command completed in {} ms", completion.duration);
 
-        debug!("ioctl_sg_io (after ): {:?}", sgbuf);
-        debug!("sense: {:?}", sense_buffer);
+        if completion.status != 0 || completion.host_status != 0 || completion.driver_status != 0 {
+            let sense = sense_buffer.get(..completion.sense_len).unwrap_or_default();
+            if let Some(sense) = Sense::parse(sense) {
+                return Err(Error::Sense(sense));
+            }
 
-        if sgbuf.info & sg::SG_INFO_OK_MASK != sg::SG_INFO_OK {
             error!(
-                "SG_IO ioctl sgbuf.info is not OK: status: {}, masked_status: {}, host_status: {}",
-                sgbuf.status, sgbuf.masked_status, sgbuf.host_status
+                "SG_IO failed: status: {:#x}, host_status: {:#x}, driver_status: {:#x}",
+                completion.status, completion.host_status, completion.driver_status
             );
-
-            if sgbuf.sb_len_wr > 0 {
-                error!(
-                    "SG_IO sense data: {:?}",
-                    sense_buffer.get(..sgbuf.sb_len_wr as usize)
-                );
+            if !sense.is_empty() {
+                error!("SG_IO sense data: {:02x?}", sense);
             }
 
-            return Err(Error::SgIoError);
+            return Err(Error::SgIoError {
+                status: completion.status,
+                host_status: completion.host_status,
+                driver_status: completion.driver_status,
+            });
         }
 
-        if let TransferBuffer::FromDevice(bfr) = xfer {
-            bfr.copy_from_slice(&data[..bfr.len()]);
+        if completion.resid > 0 {
+            return Err(Error::ShortTransfer(completion.resid as usize));
         }
 
         Ok(())
@@ -225,4 +530,33 @@ impl Backend for Device {
     fn transfer_from_device(&mut self, cdb: &[u8], data: &mut [u8]) -> Result<(), Error> {
         self.ioctl_sg_io(cdb, TransferBuffer::FromDevice(data))
     }
+
+    fn transfer_reset(&mut self, cdb: &[u8]) -> Result<(), Error> {
+        match self.ioctl_sg_io(cdb, TransferBuffer::None) {
+            // anything else, e.g. DID_ERROR or a plain CHECK CONDITION, means the
+            // device rejected the reload and is still running the old firmware
+            Err(Error::Nix(Errno::ENODEV | Errno::EIO))
+            | Err(Error::SgIoError {
+                host_status: DID_NO_CONNECT,
+                ..
+            }) => {
+                debug!("device disconnected after reset");
+                Ok(())
+            }
+            res => res,
+        }
+    }
+
+    fn set_policy(&mut self, policy: &TransferPolicy) {
+        self.policy = *policy;
+    }
+
+    fn transport(&self) -> String {
+        match self.info.interface {
+            Interface::SgV3 => "SG_IO v3 (sg)",
+            Interface::BsgV4 => "SG_IO v4 (bsg)",
+            Interface::Block => "SG_IO v3 (block device)",
+        }
+        .to_string()
+    }
 }
@@ -15,14 +15,24 @@
  *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use crate::asm2x6x::Info;
+use crate::asm2x6x::config::Config;
+use crate::asm2x6x::convert;
+use crate::asm2x6x::filter::Filter;
+use crate::asm2x6x::firmware::Firmware;
+use crate::asm2x6x::policy::TransferPolicy;
+use crate::asm2x6x::snapshot::Snapshot;
+use crate::asm2x6x::update::{self, Backup};
+use crate::asm2x6x::{run_parallel, Device, DeviceDetails, FWVersion, Info, Model};
 use asm2x6xtool::*;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use env_logger::{Builder, Env};
-use log::info;
+use log::{debug, error, info, warn};
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[derive(Subcommand)]
 enum Commands {
@@ -32,27 +42,240 @@ enum Commands {
         output: PathBuf,
     },
 
+    /// write firmware from file to device
+    WriteFirmware {
+        /// file to read firmware from
+        input: PathBuf,
+    },
+
+    /// back up the current firmware and configuration, then write, verify and reload
+    /// new firmware, restoring the old one if any of that fails
+    UpdateFirmware {
+        /// firmware image to install
+        image: PathBuf,
+
+        /// directory for the timestamped backup files
+        #[arg(long, default_value = ".")]
+        backup_dir: PathBuf,
+
+        /// firmware version the device has to report after the update, e.g.
+        /// 240115_00_01_00. The update is rolled back if it does not match
+        #[arg(long, value_name = "VERSION")]
+        expect_version: Option<FWVersion>,
+    },
+
+    /// validate a firmware image before flashing it
+    InspectFirmware {
+        /// file to read firmware from
+        input: PathBuf,
+    },
+
+    /// convert a firmware image between raw and Intel HEX files
+    ConvertFirmware {
+        /// file to read the image from
+        input: PathBuf,
+
+        /// file to write the converted image to
+        output: PathBuf,
+
+        /// format of the input: raw or hex. Detected from the contents if not given
+        #[arg(long)]
+        from: Option<convert::Format>,
+
+        /// format of the output: raw or hex
+        #[arg(long, default_value = "raw")]
+        to: convert::Format,
+    },
+
     /// read configuration from device to file
     ReadConfiguration {
         /// file to write configuration to
         output: PathBuf,
     },
 
+    /// write configuration from file to device
+    WriteConfiguration {
+        /// file to read configuration from
+        input: PathBuf,
+    },
+
+    /// print the decoded configuration from device or file
+    ShowConfiguration {
+        /// file to read configuration from instead of the device
+        input: Option<PathBuf>,
+    },
+
+    /// read and hexdump XDATA memory
+    ReadMemory {
+        /// start address (0x prefix for hex)
+        #[arg(value_parser = parse_u32)]
+        addr: u32,
+
+        /// number of bytes to read (0x prefix for hex)
+        #[arg(value_parser = parse_u32)]
+        len: u32,
+
+        /// write the raw bytes to this file instead of printing a hexdump
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// write bytes to XDATA memory
+    WriteMemory {
+        /// start address (0x prefix for hex)
+        #[arg(value_parser = parse_u32)]
+        addr: u32,
+
+        /// bytes to write (0x prefix for hex)
+        #[arg(required = true, value_parser = parse_u8)]
+        data: Vec<u8>,
+    },
+
+    /// capture the whole XDATA address space to a file
+    Snapshot {
+        /// file to write snapshot to
+        output: PathBuf,
+    },
+
+    /// show which bytes changed between two snapshots
+    DiffSnapshot {
+        /// snapshot taken before
+        a: PathBuf,
+
+        /// snapshot taken after
+        b: PathBuf,
+    },
+
+    /// soft reset the device, e.g. to apply new firmware or configuration
+    Reload,
+
     /// list all connected devices
     ListDevices,
+
+    /// show model, firmware version, configured identity and transport of a device
+    Info,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// human readable log messages
+    Text,
+    /// a single JSON document with the result on stdout, logs stay on stderr
+    Json,
 }
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// Optional device name to operate on
+    /// Output format of command results
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    /// Optional device name to operate on, as shown by list-devices. On linux this can
+    /// also be a device node like /dev/sda or a symlink in /dev/disk/by-id or by-path
     #[arg(short, long)]
     device: Option<String>,
 
+    /// Override the detected model, or the model of files that are not read from a device
+    #[arg(short, long)]
+    model: Option<Model>,
+
+    /// Record all transfers to a trace file which can be replayed with --device replay:<file>
+    #[arg(long)]
+    record: Option<PathBuf>,
+
+    /// Run info, read-firmware, read-configuration, write-firmware or
+    /// write-configuration on every matching device. Output files get the USB port path
+    /// or device name appended
+    #[arg(long)]
+    all: bool,
+
+    /// Only use devices of this model
+    #[arg(long, value_name = "MODEL")]
+    match_model: Option<Model>,
+
+    /// Only use devices running this firmware version, e.g. 240115_00_01_00
+    #[arg(long, value_name = "VERSION")]
+    match_firmware: Option<FWVersion>,
+
+    /// Only use the device at this USB port path as shown by list-devices, e.g. 2-1.3
+    #[arg(long, value_name = "PATH")]
+    match_port: Option<String>,
+
+    /// Only use devices with this USB serial number or, failing that, this serial number
+    /// decoded from the configuration (whose layout is unverified)
+    #[arg(long, value_name = "SERIAL")]
+    match_serial: Option<String>,
+
+    /// Timeout for XDATA reads and writes in milliseconds
+    #[arg(long, value_name = "MS")]
+    register_timeout: Option<u64>,
+
+    /// Timeout for configuration reads and writes in milliseconds
+    #[arg(long, value_name = "MS")]
+    config_timeout: Option<u64>,
+
+    /// Timeout for flash reads and writes in milliseconds
+    #[arg(long, value_name = "MS")]
+    flash_timeout: Option<u64>,
+
+    /// Timeout for reload in milliseconds
+    #[arg(long, value_name = "MS")]
+    reset_timeout: Option<u64>,
+
+    /// Number of times a failed configuration or flash read is repeated
+    #[arg(long)]
+    retries: Option<u32>,
+
+    /// Delay before the first retry in milliseconds, doubled for every further retry
+    #[arg(long, value_name = "MS")]
+    retry_backoff: Option<u64>,
+
+    /// Delay after every command in milliseconds
+    #[arg(long, value_name = "MS")]
+    command_delay: Option<u64>,
+
+    /// Delay after every flash region in milliseconds
+    #[arg(long, value_name = "MS")]
+    flash_delay: Option<u64>,
+
+    /// Delay after a reload before looking for the device again in milliseconds
+    #[arg(long, value_name = "MS")]
+    reconnect_delay: Option<u64>,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+fn parse_u32(s: &str) -> Result<u32, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| e.to_string())
+}
+
+fn parse_u8(s: &str) -> Result<u8, String> {
+    u8::try_from(parse_u32(s)?).map_err(|e| e.to_string())
+}
+
+fn hexdump(addr: u32, data: &[u8]) {
+    for (i, line) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = line
+            .iter()
+            .map(|&b| if b.is_ascii_graphic() { b as char } else { '.' })
+            .collect();
+
+        info!(
+            "{:05x}: {:<47}  |{}|",
+            addr as usize + i * 16,
+            hex.join(" "),
+            ascii
+        );
+    }
+}
+
 pub fn find_devices() -> Result<Vec<Box<dyn Info>>, crate::error::Error> {
     let mut devices = Vec::<Box<dyn Info>>::new();
 
@@ -61,58 +284,849 @@ pub fn find_devices() -> Result<Vec<Box<dyn Info>>, crate::error::Error> {
 
     crate::usb::find_devices(&mut devices)?;
 
+    // the kernel and libusb backends see the same chips, keep the first one found so that
+    // every chip is used once and through the kernel if it has claimed it
+    let mut seen = HashSet::new();
+    devices.retain(|info| {
+        let new = info
+            .details()
+            .physical_id()
+            .is_none_or(|id| seen.insert(id));
+        if !new {
+            debug!(
+                "{} was already found through another backend",
+                info.to_string()
+            );
+        }
+        new
+    });
+
     Ok(devices)
 }
 
-fn find_device(name: Option<String>) -> Result<asm2x6x::Device, Box<dyn std::error::Error>> {
-    let devices = find_devices()?;
+fn transfer_policy(cli: &Cli) -> TransferPolicy {
+    let default = TransferPolicy::default();
+    let ms = |value: Option<u64>, default: Duration| value.map_or(default, Duration::from_millis);
+
+    TransferPolicy {
+        register_timeout: ms(cli.register_timeout, default.register_timeout),
+        config_timeout: ms(cli.config_timeout, default.config_timeout),
+        flash_timeout: ms(cli.flash_timeout, default.flash_timeout),
+        reset_timeout: ms(cli.reset_timeout, default.reset_timeout),
+        retries: cli.retries.unwrap_or(default.retries),
+        backoff: ms(cli.retry_backoff, default.backoff),
+        command_delay: ms(cli.command_delay, default.command_delay),
+        flash_delay: ms(cli.flash_delay, default.flash_delay),
+        reconnect_delay: ms(cli.reconnect_delay, default.reconnect_delay),
+    }
+}
+
+/// The trace written for `--record`, created by the first open so that opening the device
+/// again, e.g. after a reload, appends to it instead of truncating it.
+static RECORDING: OnceLock<trace::SharedWriter> = OnceLock::new();
+
+fn open_device(info: &dyn Info, cli: &Cli) -> Result<Device, error::Error> {
+    let mut backend = info.open_with_policy(&transfer_policy(cli))?;
+
+    if let Some(record) = &cli.record {
+        backend = match RECORDING.get() {
+            Some(out) => Box::new(trace::Recorder::resume(backend, Box::new(out.clone()))),
+            None => {
+                info!("recording transfers to {}", record.display());
+                let out = trace::SharedWriter::new(Box::new(File::create(record)?));
+                let recorder = trace::Recorder::new(backend, Box::new(out.clone()))?;
+                let _ = RECORDING.set(out);
+                Box::new(recorder)
+            }
+        };
+    }
+
+    let mut device = match cli.model {
+        Some(model) => Device::with_model(backend, model),
+        None => Device::new(backend),
+    };
+    device.set_policy(transfer_policy(cli));
+
+    Ok(device)
+}
+
+/// Device names that point at a device node, e.g. sg:/dev/sg2, /dev/sda or a symlink in
+/// /dev/disk/by-id.
+#[cfg(target_os = "linux")]
+fn device_node(name: &str) -> Option<&Path> {
+    let path = ["sg:", "sd:", "bsg:"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name);
+
+    path.starts_with('/').then(|| Path::new(path))
+}
+
+fn find_info(cli: &Cli) -> Result<Box<dyn Info>, Box<dyn std::error::Error>> {
+    let name = cli.device.as_deref();
+
+    // the simulated device and traces are never picked by default and have to be requested explicitly
+    if let Some(sim_model) = name.and_then(|name| name.strip_prefix("sim:")) {
+        let sim_model = match sim_model {
+            "" => Model::ASM2464PD,
+            sim_model => sim_model.parse()?,
+        };
+        return Ok(Box::new(sim::DeviceInfo::new(sim_model)));
+    }
+
+    if let Some(path) = name.and_then(|name| name.strip_prefix("replay:")) {
+        return Ok(Box::new(trace::DeviceInfo::new(Path::new(path))?));
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Some(path) = name.and_then(device_node) {
+        return Ok(Box::new(linux::DeviceInfo::from_path(path)?));
+    }
+
+    let mut devices = find_devices()?;
 
     if devices.is_empty() {
         return Err("no devices found".into());
     }
 
     match name {
-        None => {
-            return Ok(asm2x6x::Device::new(
-                devices
-                    .into_iter()
-                    .next()
-                    .expect(
-                        "devices.is_empty() was false but devices.into_iter().next() returned None",
-                    )
-                    .open()?,
-            ));
-        }
+        None => return Ok(devices.swap_remove(0)),
         Some(name) => {
-            for device in devices.into_iter() {
-                if device.to_string() == name {
-                    return Ok(asm2x6x::Device::new(device.open()?));
-                }
+            if let Some(pos) = devices.iter().position(|device| device.to_string() == name) {
+                return Ok(devices.swap_remove(pos));
             }
         }
     }
 
-    return Err("device not found".into());
+    Err("device not found".into())
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    Builder::from_env(Env::default().default_filter_or("debug")).init();
+fn filter(cli: &Cli) -> Filter {
+    Filter {
+        model: cli.match_model,
+        firmware: cli.match_firmware,
+        port_path: cli.match_port.clone(),
+        serial: cli.match_serial.clone(),
+    }
+}
 
-    let cli = Cli::parse();
+/// An opened device along with what was known about it before opening.
+struct Target {
+    info: Box<dyn Info>,
+    name: String,
+    details: DeviceDetails,
+    device: Device,
+}
+
+impl Target {
+    fn open(info: Box<dyn Info>, cli: &Cli) -> Result<Self, error::Error> {
+        Ok(Target {
+            name: info.to_string(),
+            details: info.details(),
+            device: open_device(info.as_ref(), cli)?,
+            info,
+        })
+    }
+}
 
-    match &cli.command {
+/// Inserts the port path, or the device name if there is none, before the file extension
+/// so every device of a batch gets its own file.
+fn output_path(path: &Path, name: &str, details: &DeviceDetails) -> PathBuf {
+    let label: String = details
+        .port_path
+        .as_deref()
+        .unwrap_or(name)
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' => c,
+            _ => '_',
+        })
+        .collect();
+    let label = label.trim_matches('_');
+
+    let mut file_name = path.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(format!("-{}", label));
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+
+    path.with_file_name(file_name)
+}
+
+/// Opens every device that matches the --match-* options. With --device only that
+/// device is considered, otherwise devices that fail to open are skipped.
+fn select_devices(cli: &Cli) -> Result<Vec<Target>, Box<dyn std::error::Error>> {
+    let filter = filter(cli);
+    let explicit = cli.device.is_some();
+    let devices = match explicit {
+        true => vec![find_info(cli)?],
+        false => find_devices()?,
+    };
+
+    let mut targets = Vec::new();
+    for info in devices.into_iter() {
+        if !filter.matches_info(info.as_ref()) {
+            continue;
+        }
+
+        let name = info.to_string();
+        let mut target = match Target::open(info, cli) {
+            Ok(target) => target,
+            Err(err) if !explicit => {
+                warn!("skipping {}: {}", name, err);
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        match filter.matches_device(&mut target.device, &target.details) {
+            Ok(true) => targets.push(target),
+            Ok(false) => debug!("{} does not match", target.name),
+            Err(err) if !explicit => warn!("skipping {}: {}", target.name, err),
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(targets)
+}
+
+fn find_target(cli: &Cli) -> Result<Target, Box<dyn std::error::Error>> {
+    if filter(cli).is_empty() {
+        return Ok(Target::open(find_info(cli)?, cli)?);
+    }
+
+    let mut targets = select_devices(cli)?;
+    match targets.len() {
+        0 => Err("no device matches the selection".into()),
+        1 => Ok(targets.remove(0)),
+        n => Err(format!(
+            "{} devices match the selection, use --all or narrow it down",
+            n
+        )
+        .into()),
+    }
+}
+
+fn find_device(cli: &Cli) -> Result<Device, Box<dyn std::error::Error>> {
+    Ok(find_target(cli)?.device)
+}
+
+fn device_record(name: &str, model: Model, details: &DeviceDetails) -> json::Value {
+    let backend = name.split(':').next().unwrap_or_default();
+
+    json::object([
+        ("name", name.into()),
+        ("model", model.to_string().into()),
+        ("backend", backend.into()),
+        (
+            "usb_id",
+            details
+                .usb_id
+                .map(|(vid, pid)| format!("{:04x}:{:04x}", vid, pid))
+                .into(),
+        ),
+        ("port_path", details.port_path.clone().into()),
+        ("speed", details.speed.clone().into()),
+        ("serial", details.serial.clone().into()),
+        ("revision", details.revision.clone().into()),
+    ])
+}
+
+fn config_record(config: &Config) -> json::Value {
+    json::object([
+        ("vid", config.vid.into()),
+        ("pid", config.pid.into()),
+        ("power_flags", config.power_flags.into()),
+        ("port_flags", config.port_flags.into()),
+        ("vendor", config.vendor.as_str().into()),
+        ("product", config.product.as_str().into()),
+        ("serial", config.serial.as_str().into()),
+        ("layout_verified", config.layout_verified().into()),
+    ])
+}
+
+fn version_record(version: &FWVersion) -> json::Value {
+    json::object([
+        ("version", version.to_string().into()),
+        (
+            "build_date",
+            version
+                .build_date()
+                .map(|(year, month, day)| format!("{:04}-{:02}-{:02}", year, month, day))
+                .into(),
+        ),
+        ("a", version.a().into()),
+        ("b", version.b().into()),
+        ("c", version.c().into()),
+    ])
+}
+
+/// Returned by batch runs in which some devices failed. The results of all devices are
+/// still reported.
+#[derive(Debug)]
+struct BatchFailed {
+    failed: usize,
+    total: usize,
+    results: json::Value,
+}
+
+impl std::fmt::Display for BatchFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} of {} devices failed", self.failed, self.total)
+    }
+}
+
+impl std::error::Error for BatchFailed {}
+
+fn print_summary(rows: &[[String; 4]]) {
+    let header = ["DEVICE", "PORT", "SERIAL", "RESULT"].map(String::from);
+    let mut widths = header.clone().map(|column| column.len());
+    for row in rows.iter() {
+        for (width, column) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(column.len());
+        }
+    }
+
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        info!(
+            "{:<w0$}  {:<w1$}  {:<w2$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+        );
+    }
+}
+
+/// Runs a command on every matching device at once. Every device gets a worker thread
+/// so slow flash operations overlap.
+fn run_batch(cli: &Cli) -> Result<json::Value, Box<dyn std::error::Error>> {
+    if !matches!(
+        cli.command,
+        Commands::Info
+            | Commands::ReadFirmware { .. }
+            | Commands::ReadConfiguration { .. }
+            | Commands::WriteFirmware { .. }
+            | Commands::WriteConfiguration { .. }
+    ) {
+        return Err("--all only works with info, read-firmware, read-configuration, write-firmware and write-configuration".into());
+    }
+
+    let targets = select_devices(cli)?;
+    if targets.is_empty() {
+        return Err("no device matches the selection".into());
+    }
+    info!("running on {} devices", targets.len());
+
+    let transports: Vec<String> = targets.iter().map(|t| t.device.transport()).collect();
+    let (targets, mut devices): (Vec<_>, Vec<_>) = targets
+        .into_iter()
+        .map(|t| ((t.name, t.details), t.device))
+        .unzip();
+
+    // every device yields its result and a line for the summary
+    type Outcome = Result<(json::Value, String), error::Error>;
+    let outcomes: Vec<Outcome> = match &cli.command {
+        Commands::Info => run_parallel(&mut devices, |i, device| -> Outcome {
+            let version = device.read_fw_version()?;
+            let config = Config::new(&device.read_config()?, device.model());
+
+            let summary = format!("firmware {}, serial {:?}", version, config.serial);
+            let result = json::object([
+                ("model", device.model().to_string().into()),
+                ("transport", transports[i].clone().into()),
+                ("firmware", version_record(&version)),
+                ("configuration", config_record(&config)),
+            ]);
+            Ok((result, summary))
+        }),
+
+        Commands::ReadFirmware { output } | Commands::ReadConfiguration { output } => {
+            let firmware = matches!(cli.command, Commands::ReadFirmware { .. });
+
+            run_parallel(&mut devices, |i, device| -> Outcome {
+                let (name, details) = &targets[i];
+                let path = output_path(output, name, details);
+                let bfr = match firmware {
+                    true => device.read_firmware()?,
+                    false => device.read_config()?.to_vec(),
+                };
+                File::create(&path)?.write_all(&bfr)?;
+
+                let summary = format!("wrote {}", path.display());
+                let result = json::object([
+                    ("output", path.display().to_string().into()),
+                    ("size", bfr.len().into()),
+                ]);
+                Ok((result, summary))
+            })
+        }
+
+        Commands::WriteFirmware { input } => {
+            let firmware = std::fs::read(input)?;
+
+            run_parallel(&mut devices, |_, device| -> Outcome {
+                device.write_firmware(&firmware)?;
+                let result = json::object([("size", firmware.len().into())]);
+                Ok((result, String::from("firmware written")))
+            })
+        }
+
+        Commands::WriteConfiguration { input } => {
+            let config = std::fs::read(input)?;
+            let config = <[u8; 0x80]>::try_from(config.as_slice())
+                .map_err(|_| error::Error::InvalidConfigSize(config.len()))?;
+
+            run_parallel(&mut devices, |_, device| -> Outcome {
+                device.write_config(&config)?;
+                let result = json::object([(
+                    "configuration",
+                    config_record(&Config::new(&config, device.model())),
+                )]);
+                Ok((result, String::from("configuration written")))
+            })
+        }
+
+        _ => unreachable!("checked above"),
+    };
+
+    let mut rows = Vec::new();
+    let mut results = Vec::new();
+    let mut failed = 0;
+    for (((name, details), device), outcome) in targets.iter().zip(devices.iter()).zip(outcomes) {
+        let (result, error, summary) = match outcome {
+            Ok((result, summary)) => (result, json::Value::Null, format!("ok: {}", summary)),
+            Err(err) => {
+                failed += 1;
+                error!("{}: {}", name, err);
+                (
+                    json::Value::Null,
+                    err.to_string().into(),
+                    format!("FAILED: {}", err),
+                )
+            }
+        };
+
+        let dash = || String::from("-");
+        rows.push([
+            name.clone(),
+            details.port_path.clone().unwrap_or_else(dash),
+            details.serial.clone().unwrap_or_else(dash),
+            summary,
+        ]);
+        results.push(json::object([
+            ("device", device_record(name, device.model(), details)),
+            ("ok", (error == json::Value::Null).into()),
+            ("result", result),
+            ("error", error),
+        ]));
+    }
+
+    print_summary(&rows);
+
+    let results = json::Value::from(results);
+    match failed {
+        0 => Ok(results),
+        failed => Err(BatchFailed {
+            failed,
+            total: rows.len(),
+            results,
+        }
+        .into()),
+    }
+}
+
+/// Runs the selected command. Progress and human readable results are logged, the
+/// returned value is what `--format json` prints.
+fn run(cli: &Cli) -> Result<json::Value, Box<dyn std::error::Error>> {
+    let file_model = cli.model.unwrap_or(Model::ASM2464PD);
+
+    if cli.all {
+        return run_batch(cli);
+    }
+
+    let result = match &cli.command {
         Commands::ReadFirmware { output } => {
-            let mut device = find_device(cli.device)?;
+            let mut device = find_device(cli)?;
 
             info!("reading firmware");
-            File::create(output)?.write_all(&device.read_firmware()?)?;
+            let firmware = device.read_firmware()?;
+            File::create(output)?.write_all(&firmware)?;
+
+            json::object([
+                ("output", output.display().to_string().into()),
+                ("size", firmware.len().into()),
+            ])
+        }
+
+        Commands::WriteFirmware { input } => {
+            let firmware = std::fs::read(input)?;
+            let mut device = find_device(cli)?;
+
+            info!("writing firmware");
+            device.write_firmware(&firmware)?;
+
+            json::object([("size", firmware.len().into())])
+        }
+
+        Commands::UpdateFirmware {
+            image,
+            backup_dir,
+            expect_version,
+        } => {
+            let Target {
+                info,
+                name,
+                details,
+                mut device,
+            } = find_target(cli)?;
+            let image = Firmware::new(std::fs::read(image)?, device.model());
+
+            let previous = device.read_fw_version()?;
+            match expect_version {
+                Some(version) => info!("updating firmware {} to {}", previous, version),
+                None => info!("updating firmware {}", previous),
+            }
+
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let firmware_backup = output_path(
+                &backup_dir.join(format!("firmware-{}.bin", timestamp)),
+                &name,
+                &details,
+            );
+            let config_backup = output_path(
+                &backup_dir.join(format!("config-{}.bin", timestamp)),
+                &name,
+                &details,
+            );
+
+            let save = |backup: &Backup| -> Result<(), error::Error> {
+                File::create(&firmware_backup)?.write_all(&backup.firmware)?;
+                File::create(&config_backup)?.write_all(&backup.config)?;
+                info!(
+                    "saved backup to {} and {}",
+                    firmware_backup.display(),
+                    config_backup.display()
+                );
+                Ok(())
+            };
+
+            let reopen = || match &details.port_path {
+                // the USB address and device node usually change after a reload
+                Some(port_path) => {
+                    let devices = find_devices()?;
+                    let info = devices
+                        .iter()
+                        .find(|info| info.details().port_path.as_ref() == Some(port_path))
+                        .ok_or(error::Error::DeviceNotFound)?;
+                    open_device(info.as_ref(), cli)
+                }
+                None => open_device(info.as_ref(), cli),
+            };
+
+            let mut device =
+                update::update_firmware(device, &image, *expect_version, save, reopen)?;
+            let version = device.read_fw_version()?;
+
+            json::object([
+                ("device", device_record(&name, device.model(), &details)),
+                ("previous", version_record(&previous)),
+                ("firmware", version_record(&version)),
+                (
+                    "firmware_backup",
+                    firmware_backup.display().to_string().into(),
+                ),
+                ("config_backup", config_backup.display().to_string().into()),
+            ])
+        }
+
+        Commands::InspectFirmware { input } => {
+            let firmware = Firmware::new(std::fs::read(input)?, file_model);
+            info!("size: {:#x}", firmware.as_bytes().len());
+
+            let problems = firmware.validate();
+            for problem in problems.iter() {
+                error!("{}", problem);
+            }
+            if !problems.is_empty() {
+                return Err(error::Error::InvalidFirmware.into());
+            }
+
+            info!("firmware image is valid");
+
+            json::object([
+                ("model", firmware.model().to_string().into()),
+                ("size", firmware.as_bytes().len().into()),
+            ])
+        }
+
+        Commands::ConvertFirmware {
+            input,
+            output,
+            from,
+            to,
+        } => {
+            let data = std::fs::read(input)?;
+            let from = from.unwrap_or_else(|| convert::Format::detect(&data));
+            info!("reading {} as {}", input.display(), from);
+
+            let firmware = match from {
+                convert::Format::Raw => Firmware::new(data, file_model),
+                convert::Format::IntelHex => {
+                    let text = String::from_utf8(data).map_err(|_| error::Error::InvalidHex(1))?;
+                    convert::read_hex(&text, file_model)?
+                }
+            };
+
+            // a converted image is meant to be flashed, so it has to pass the same checks
+            let problems = firmware.validate();
+            for problem in problems.iter() {
+                error!("{}", problem);
+            }
+            if !problems.is_empty() {
+                return Err(error::Error::InvalidFirmware.into());
+            }
+
+            let bfr = match to {
+                convert::Format::Raw => firmware.as_bytes().to_vec(),
+                convert::Format::IntelHex => convert::write_hex(&firmware)?.into_bytes(),
+            };
+            File::create(output)?.write_all(&bfr)?;
+
+            json::object([
+                ("from", from.to_string().into()),
+                ("to", to.to_string().into()),
+                ("output", output.display().to_string().into()),
+            ])
         }
 
         Commands::ReadConfiguration { output } => {
-            let mut device = find_device(cli.device)?;
+            let mut device = find_device(cli)?;
 
             info!("reading configuration");
-            File::create(output)?.write_all(&device.read_config()?)?;
+            let config = device.read_config()?;
+            File::create(output)?.write_all(&config)?;
+
+            json::object([
+                ("output", output.display().to_string().into()),
+                (
+                    "configuration",
+                    config_record(&Config::new(&config, device.model())),
+                ),
+            ])
+        }
+
+        Commands::WriteConfiguration { input } => {
+            let config = std::fs::read(input)?;
+            let config = <[u8; 0x80]>::try_from(config.as_slice())
+                .map_err(|_| error::Error::InvalidConfigSize(config.len()))?;
+            let mut device = find_device(cli)?;
+
+            info!("writing configuration");
+            device.write_config(&config)?;
+
+            json::object([(
+                "configuration",
+                config_record(&Config::new(&config, device.model())),
+            )])
+        }
+
+        Commands::ShowConfiguration { input } => {
+            let config = match input {
+                Some(input) => Config::parse(&std::fs::read(input)?, file_model)?,
+                None => {
+                    let mut device = find_device(cli)?;
+
+                    info!("reading configuration");
+                    Config::new(&device.read_config()?, device.model())
+                }
+            };
+
+            for line in config.to_string().lines() {
+                info!("{}", line);
+            }
+
+            config_record(&config)
+        }
+
+        Commands::ReadMemory { addr, len, output } => {
+            // checked before the buffer is allocated, len can be up to 4 GiB
+            Device::check_xdata_range(*addr, *len as usize)?;
+
+            let mut device = find_device(cli)?;
+            let mut bfr = vec![0_u8; *len as usize];
+
+            info!("reading {:#x} bytes from {:#07x}", len, addr);
+            let mut last_percent = 0;
+            device.read_with_progress(*addr, &mut bfr, |done, total| {
+                let percent = done * 100 / total;
+                if percent / 10 != last_percent / 10 {
+                    info!("read {:#x}/{:#x} bytes ({}%)", done, total, percent);
+                }
+                last_percent = percent;
+            })?;
+
+            match output {
+                Some(output) => {
+                    File::create(output)?.write_all(&bfr)?;
+                    json::object([
+                        ("addr", (*addr).into()),
+                        ("output", output.display().to_string().into()),
+                    ])
+                }
+                None => {
+                    if cli.format == Format::Text {
+                        hexdump(*addr, &bfr);
+                    }
+                    json::object([("addr", (*addr).into()), ("data", json::hex(&bfr))])
+                }
+            }
+        }
+
+        Commands::WriteMemory { addr, data } => {
+            // checked up front so that a range past the end is not written partially
+            Device::check_xdata_range(*addr, data.len())?;
+
+            let mut device = find_device(cli)?;
+
+            info!("writing {:#x} bytes to {:#07x}", data.len(), addr);
+            for (i, value) in data.iter().enumerate() {
+                device.write(addr + i as u32, *value)?;
+            }
+
+            json::object([("addr", (*addr).into()), ("data", json::hex(data))])
+        }
+
+        Commands::Snapshot { output } => {
+            let mut device = find_device(cli)?;
+
+            info!("capturing XDATA snapshot");
+            let mut last_percent = 0;
+            let snapshot = Snapshot::capture(&mut device, |done, total| {
+                let percent = done * 100 / total;
+                if percent / 10 != last_percent / 10 {
+                    info!("read {:#x}/{:#x} bytes ({}%)", done, total, percent);
+                }
+                last_percent = percent;
+            })?;
+
+            snapshot.write_to(std::io::BufWriter::new(File::create(output)?))?;
+
+            json::object([
+                ("output", output.display().to_string().into()),
+                ("model", snapshot.model.to_string().into()),
+                ("firmware", snapshot.version.to_string().into()),
+                ("timestamp", snapshot.timestamp.into()),
+            ])
+        }
+
+        Commands::DiffSnapshot { a, b } => {
+            let a = Snapshot::read_from(File::open(a)?)?;
+            let b = Snapshot::read_from(File::open(b)?)?;
+
+            if a.model != b.model {
+                warn!(
+                    "snapshots are from different models: {} vs {}",
+                    a.model, b.model
+                );
+            }
+            if a.version != b.version {
+                warn!(
+                    "snapshots are from different firmware versions: {} vs {}",
+                    a.version, b.version
+                );
+            }
+
+            let changes = a.diff(&b);
+            for change in changes.iter() {
+                let hex = |bfr: &[u8]| {
+                    bfr.iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                };
+                info!(
+                    "{:05x}: {} -> {}",
+                    change.addr,
+                    hex(&change.old),
+                    hex(&change.new)
+                );
+            }
+
+            info!(
+                "{} changed bytes in {} ranges",
+                changes.iter().map(|c| c.old.len()).sum::<usize>(),
+                changes.len()
+            );
+
+            let changes: Vec<json::Value> = changes
+                .iter()
+                .map(|change| {
+                    json::object([
+                        ("addr", change.addr.into()),
+                        ("old", json::hex(&change.old)),
+                        ("new", json::hex(&change.new)),
+                    ])
+                })
+                .collect();
+
+            json::object([("changes", changes.into())])
+        }
+
+        Commands::Reload => {
+            let mut device = find_device(cli)?;
+
+            info!("reloading device");
+            device.reload()?;
+
+            json::Value::Null
+        }
+
+        Commands::Info => {
+            let Target {
+                name,
+                details,
+                mut device,
+                ..
+            } = find_target(cli)?;
+
+            let version = device.read_fw_version()?;
+            let config = Config::new(&device.read_config()?, device.model());
+
+            info!("device: {}", name);
+            info!("model: {}", device.model());
+            info!("transport: {}", device.transport());
+            if !details.to_string().is_empty() {
+                info!("details: {}", details);
+            }
+            info!("firmware version: {}", version);
+            match version.build_date() {
+                Some((year, month, day)) => {
+                    info!("  build date: {:04}-{:02}-{:02}", year, month, day)
+                }
+                None => warn!("  build date: invalid"),
+            }
+            info!(
+                "  a: {:#04x}, b: {:#04x}, c: {:#04x}",
+                version.a(),
+                version.b(),
+                version.c()
+            );
+            for line in config.to_string().lines() {
+                info!("{}", line);
+            }
+
+            json::object([
+                ("device", device_record(&name, device.model(), &details)),
+                ("model", device.model().to_string().into()),
+                ("transport", device.transport().into()),
+                ("firmware", version_record(&version)),
+                ("configuration", config_record(&config)),
+            ])
         }
 
         Commands::ListDevices => {
@@ -122,10 +1136,55 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 info!("no devices found");
             }
 
-            for device in devices.into_iter() {
-                info!("{} - {}", device.to_string(), device.model());
+            for device in devices.iter() {
+                let details = device.details().to_string();
+                if details.is_empty() {
+                    info!("{} - {}", device.to_string(), device.model());
+                } else {
+                    info!("{} - {} ({})", device.to_string(), device.model(), details);
+                }
             }
+
+            devices
+                .iter()
+                .map(|device| device_record(&device.to_string(), device.model(), &device.details()))
+                .collect::<Vec<_>>()
+                .into()
         }
+    };
+
+    Ok(result)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    Builder::from_env(Env::default().default_filter_or("debug")).init();
+
+    let cli = Cli::parse();
+    let result = run(&cli);
+
+    if cli.format == Format::Text {
+        return result.map(|_| ());
+    }
+
+    // logs keep going to stderr, stdout only ever gets this single document
+    let output = match &result {
+        Ok(value) => json::object([("ok", true.into()), ("result", value.clone())]),
+        Err(err) => json::object([
+            ("ok", false.into()),
+            ("error", err.to_string().into()),
+            // batch runs still report the devices that succeeded
+            (
+                "result",
+                err.downcast_ref::<BatchFailed>()
+                    .map(|batch| batch.results.clone())
+                    .into(),
+            ),
+        ]),
+    };
+    println!("{}", output);
+
+    if result.is_err() {
+        std::process::exit(1);
     }
 
     Ok(())
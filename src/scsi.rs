@@ -0,0 +1,254 @@
+/*
+ *  asm2x6xtool - configuration and firmware management for ASM2x6x chips
+ *  Copyright (C) 2024 Sven Peter <sven@svenpeter.dev>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Decoding of SCSI sense data returned along with a CHECK CONDITION status.
+
+use std::fmt::{Display, Formatter};
+
+pub const REQUEST_SENSE: u8 = 0x03;
+pub const SENSE_BUFFER_SIZE: usize = 0xfc;
+
+const RESPONSE_FIXED_CURRENT: u8 = 0x70;
+const RESPONSE_FIXED_DEFERRED: u8 = 0x71;
+const RESPONSE_DESCRIPTOR_CURRENT: u8 = 0x72;
+const RESPONSE_DESCRIPTOR_DEFERRED: u8 = 0x73;
+
+const DESCRIPTOR_INFORMATION: u8 = 0x00;
+const DESCRIPTOR_VENDOR_START: u8 = 0x80;
+
+const ASC_INVALID_OPCODE: u8 = 0x20;
+const ASC_INVALID_FIELD_IN_CDB: u8 = 0x24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SenseKey {
+    NoSense,
+    RecoveredError,
+    NotReady,
+    MediumError,
+    HardwareError,
+    IllegalRequest,
+    UnitAttention,
+    DataProtect,
+    BlankCheck,
+    VendorSpecific,
+    CopyAborted,
+    AbortedCommand,
+    Obsolete,
+    VolumeOverflow,
+    Miscompare,
+    Completed,
+}
+
+impl From<u8> for SenseKey {
+    fn from(key: u8) -> Self {
+        match key & 0x0f {
+            0x0 => SenseKey::NoSense,
+            0x1 => SenseKey::RecoveredError,
+            0x2 => SenseKey::NotReady,
+            0x3 => SenseKey::MediumError,
+            0x4 => SenseKey::HardwareError,
+            0x5 => SenseKey::IllegalRequest,
+            0x6 => SenseKey::UnitAttention,
+            0x7 => SenseKey::DataProtect,
+            0x8 => SenseKey::BlankCheck,
+            0x9 => SenseKey::VendorSpecific,
+            0xa => SenseKey::CopyAborted,
+            0xb => SenseKey::AbortedCommand,
+            0xc => SenseKey::Obsolete,
+            0xd => SenseKey::VolumeOverflow,
+            0xe => SenseKey::Miscompare,
+            _ => SenseKey::Completed,
+        }
+    }
+}
+
+impl Display for SenseKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            SenseKey::NoSense => "NO SENSE",
+            SenseKey::RecoveredError => "RECOVERED ERROR",
+            SenseKey::NotReady => "NOT READY",
+            SenseKey::MediumError => "MEDIUM ERROR",
+            SenseKey::HardwareError => "HARDWARE ERROR",
+            SenseKey::IllegalRequest => "ILLEGAL REQUEST",
+            SenseKey::UnitAttention => "UNIT ATTENTION",
+            SenseKey::DataProtect => "DATA PROTECT",
+            SenseKey::BlankCheck => "BLANK CHECK",
+            SenseKey::VendorSpecific => "VENDOR SPECIFIC",
+            SenseKey::CopyAborted => "COPY ABORTED",
+            SenseKey::AbortedCommand => "ABORTED COMMAND",
+            SenseKey::Obsolete => "OBSOLETE",
+            SenseKey::VolumeOverflow => "VOLUME OVERFLOW",
+            SenseKey::Miscompare => "MISCOMPARE",
+            SenseKey::Completed => "COMPLETED",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Decoded sense data in either fixed or descriptor format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sense {
+    pub key: SenseKey,
+    pub asc: u8,
+    pub ascq: u8,
+    /// the error belongs to an earlier command
+    pub deferred: bool,
+    /// contents of the information field if the device marked it as valid
+    pub information: Option<u64>,
+    /// additional sense bytes of the fixed format or vendor specific descriptors
+    pub vendor: Vec<u8>,
+}
+
+impl Sense {
+    pub fn new(key: SenseKey, asc: u8, ascq: u8) -> Self {
+        Sense {
+            key,
+            asc,
+            ascq,
+            deferred: false,
+            information: None,
+            vendor: Vec::new(),
+        }
+    }
+
+    /// Returns None if `bfr` does not start with a known response code.
+    pub fn parse(bfr: &[u8]) -> Option<Self> {
+        match bfr.first()? & 0x7f {
+            code @ (RESPONSE_FIXED_CURRENT | RESPONSE_FIXED_DEFERRED) => {
+                Self::parse_fixed(bfr, code == RESPONSE_FIXED_DEFERRED)
+            }
+            code @ (RESPONSE_DESCRIPTOR_CURRENT | RESPONSE_DESCRIPTOR_DEFERRED) => {
+                Self::parse_descriptor(bfr, code == RESPONSE_DESCRIPTOR_DEFERRED)
+            }
+            _ => None,
+        }
+    }
+
+    fn parse_fixed(bfr: &[u8], deferred: bool) -> Option<Self> {
+        let byte = |i: usize| bfr.get(i).copied().unwrap_or(0);
+
+        // the additional sense length may claim more than the device actually sent
+        let len = (8 + byte(7) as usize).min(bfr.len());
+
+        let information = if bfr[0] & 0x80 != 0 && len >= 7 {
+            Some(u32::from_be_bytes([bfr[3], bfr[4], bfr[5], bfr[6]]) as u64)
+        } else {
+            None
+        };
+
+        Some(Sense {
+            key: SenseKey::from(*bfr.get(2)?),
+            asc: byte(12),
+            ascq: byte(13),
+            deferred,
+            information,
+            vendor: bfr.get(18..len).unwrap_or_default().to_vec(),
+        })
+    }
+
+    fn parse_descriptor(bfr: &[u8], deferred: bool) -> Option<Self> {
+        if bfr.len() < 4 {
+            return None;
+        }
+
+        let mut sense = Sense {
+            deferred,
+            ..Sense::new(SenseKey::from(bfr[1]), bfr[2], bfr[3])
+        };
+
+        let len = (8 + bfr.get(7).copied().unwrap_or(0) as usize).min(bfr.len());
+        let mut descriptors = bfr.get(8..len).unwrap_or_default();
+
+        while descriptors.len() >= 2 {
+            let desc_len = (2 + descriptors[1] as usize).min(descriptors.len());
+            let (desc, rest) = descriptors.split_at(desc_len);
+
+            match desc[0] {
+                DESCRIPTOR_INFORMATION if desc.len() >= 12 && desc[2] & 0x80 != 0 => {
+                    let mut information = [0_u8; 8];
+                    information.copy_from_slice(&desc[4..12]);
+                    sense.information = Some(u64::from_be_bytes(information));
+                }
+                DESCRIPTOR_VENDOR_START.. => sense.vendor.extend_from_slice(desc),
+                _ => (),
+            }
+
+            descriptors = rest;
+        }
+
+        Some(sense)
+    }
+
+    /// The device does not implement the command or one of its parameters.
+    pub fn is_unsupported_command(&self) -> bool {
+        self.key == SenseKey::IllegalRequest
+            && matches!(self.asc, ASC_INVALID_OPCODE | ASC_INVALID_FIELD_IN_CDB)
+    }
+
+    /// The device is busy, e.g. with a flash operation, and the command may succeed
+    /// later.
+    pub fn is_not_ready(&self) -> bool {
+        self.key == SenseKey::NotReady
+    }
+
+    pub fn description(&self) -> Option<&'static str> {
+        let description = match (self.asc, self.ascq) {
+            (0x00, 0x00) => "no additional sense information",
+            (0x04, 0x00) => "logical unit not ready, cause not reportable",
+            (0x04, 0x01) => "logical unit is in process of becoming ready",
+            (0x04, 0x07) => "logical unit not ready, operation in progress",
+            (0x0c, 0x00) => "write error",
+            (0x11, 0x00) => "unrecovered read error",
+            (0x1a, 0x00) => "parameter list length error",
+            (ASC_INVALID_OPCODE, 0x00) => "invalid command operation code",
+            (0x21, 0x00) => "logical block address out of range",
+            (ASC_INVALID_FIELD_IN_CDB, 0x00) => "invalid field in CDB",
+            (0x25, 0x00) => "logical unit not supported",
+            (0x26, 0x00) => "invalid field in parameter list",
+            (0x28, 0x00) => "not ready to ready change, medium may have changed",
+            (0x29, 0x00) => "power on, reset, or bus device reset occurred",
+            (0x3a, 0x00) => "medium not present",
+            (0x3f, 0x01) => "microcode has been changed",
+            (0x44, 0x00) => "internal target failure",
+            _ => return None,
+        };
+        Some(description)
+    }
+}
+
+impl Display for Sense {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.key)?;
+        if let Some(description) = self.description() {
+            write!(f, ": {}", description)?;
+        }
+        write!(f, " (asc {:#04x}, ascq {:#04x})", self.asc, self.ascq)?;
+
+        if self.deferred {
+            write!(f, ", deferred")?;
+        }
+        if let Some(information) = self.information {
+            write!(f, ", information {:#x}", information)?;
+        }
+        if !self.vendor.is_empty() {
+            write!(f, ", vendor data {:02x?}", self.vendor)?;
+        }
+
+        Ok(())
+    }
+}
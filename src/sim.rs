@@ -0,0 +1,269 @@
+/*
+ *  asm2x6xtool - configuration and firmware management for ASM2x6x chips
+ *  Copyright (C) 2024 Sven Peter <sven@svenpeter.dev>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::asm2x6x::config::{Config, CONFIG_SIZE};
+use crate::asm2x6x::{Backend, Command, Info, Model, XDATA_SIZE};
+use crate::error::Error;
+use crate::scsi::{Sense, SenseKey};
+use log::debug;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+const XDATA_BASE: u32 = 0x500000;
+const FW_VERSION_ADDR: usize = 0x07f0;
+
+/// Where the simulated bootloader takes the version of the running firmware from. This is
+/// a convention of the simulator only, the location in a real image is not known.
+pub const VERSION_OFFSET: usize = 0x0200;
+
+// what a real device reports when it rejects a command
+fn unsupported_command() -> Error {
+    Error::Sense(Sense::new(SenseKey::IllegalRequest, 0x20, 0x00))
+}
+
+fn invalid_field() -> Error {
+    Error::Sense(Sense::new(SenseKey::IllegalRequest, 0x24, 0x00))
+}
+
+/// Chip state shared between all handles opened from the same `DeviceInfo`.
+#[derive(Debug)]
+pub struct State {
+    pub model: Model,
+    pub xdata: Vec<u8>,
+    pub config: [u8; CONFIG_SIZE],
+    pub flash: Vec<u8>,
+    pub reloads: usize,
+}
+
+impl State {
+    fn boot(&mut self) {
+        // the bootloader copies the version of the running firmware into XDATA
+        let version = &self.flash[VERSION_OFFSET..VERSION_OFFSET + 6];
+        self.xdata[FW_VERSION_ADDR..FW_VERSION_ADDR + 6].copy_from_slice(version);
+    }
+}
+
+impl State {
+    pub fn new(model: Model) -> Self {
+        // arbitrary contents, only the version is looked at
+        let mut flash: Vec<u8> = (0..model.info().flash_size)
+            .map(|i| (i * 7 + 3) as u8)
+            .collect();
+        flash[VERSION_OFFSET..VERSION_OFFSET + 6]
+            .copy_from_slice(&[0x24, 0x01, 0x15, 0x00, 0x01, 0x00]);
+
+        let mut config = Config::new(&[0_u8; CONFIG_SIZE], model);
+        (config.vid, config.pid) = model.info().usb_ids[0];
+        config.vendor = "ASMedia".into();
+        config.product = model.to_string();
+        config.serial = "000000000001".into();
+
+        let mut state = State {
+            model,
+            xdata: vec![0_u8; XDATA_SIZE as usize],
+            config: config.to_bytes().expect("simulated config is valid"),
+            flash,
+            reloads: 0,
+        };
+        state.boot();
+        state
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub model: Model,
+    pub state: Arc<Mutex<State>>,
+}
+
+pub struct SimulatedBackend {
+    model: Model,
+    state: Arc<Mutex<State>>,
+}
+
+impl DeviceInfo {
+    pub fn new(model: Model) -> Self {
+        DeviceInfo {
+            model,
+            state: Arc::new(Mutex::new(State::new(model))),
+        }
+    }
+}
+
+impl Default for DeviceInfo {
+    fn default() -> Self {
+        DeviceInfo::new(Model::ASM2464PD)
+    }
+}
+
+impl Display for DeviceInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "sim:{}", self.model)
+    }
+}
+
+impl Info for DeviceInfo {
+    fn open(&self) -> Result<Box<dyn Backend>, Error> {
+        Ok(Box::new(SimulatedBackend {
+            model: self.model,
+            state: self.state.clone(),
+        }))
+    }
+
+    fn model(&self) -> Model {
+        self.model
+    }
+}
+
+fn command(cdb: &[u8]) -> Result<Command, Error> {
+    if cdb.len() != 6 {
+        return Err(Error::InvalidCDB);
+    }
+
+    Command::try_from(cdb[0]).map_err(|_| unsupported_command())
+}
+
+fn xdata_addr(cdb: &[u8]) -> Result<usize, Error> {
+    let addr = u32::from_be_bytes([0, cdb[2], cdb[3], cdb[4]]);
+    if addr & !0x01ffff != XDATA_BASE {
+        return Err(invalid_field());
+    }
+
+    Ok((addr & 0x01ffff) as usize)
+}
+
+fn flash_region(model: Model, cdb: &[u8], len: usize) -> Result<Range<usize>, Error> {
+    let region = model
+        .info()
+        .firmware_regions
+        .iter()
+        .find(|region| region.selector == cdb[1])
+        .ok_or_else(invalid_field)?;
+    let cdb_len = u32::from_be_bytes([cdb[2], cdb[3], cdb[4], cdb[5]]) as usize;
+
+    if cdb_len != len || len > region.range.len() {
+        return Err(invalid_field());
+    }
+
+    Ok(region.range.start..region.range.start + len)
+}
+
+impl SimulatedBackend {
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .expect("simulated device state is poisoned")
+    }
+}
+
+impl Backend for SimulatedBackend {
+    fn model(&self) -> Model {
+        self.model
+    }
+
+    fn transfer(&mut self, cdb: &[u8]) -> Result<(), Error> {
+        debug!("sim: transfer {:02x?}", cdb);
+        let command = command(cdb)?;
+
+        let mut state = self.state();
+        match command {
+            Command::Write => {
+                let addr = xdata_addr(cdb)?;
+                state.xdata[addr] = cdb[1];
+                Ok(())
+            }
+            _ => Err(unsupported_command()),
+        }
+    }
+
+    fn transfer_to_device(&mut self, cdb: &[u8], data: &[u8]) -> Result<(), Error> {
+        debug!("sim: transfer_to_device {:02x?}, {} bytes", cdb, data.len());
+        let command = command(cdb)?;
+
+        let mut state = self.state();
+        match command {
+            Command::ConfigWrite if cdb[1] == 0x50 && data.len() == CONFIG_SIZE => {
+                state.config.copy_from_slice(data);
+                Ok(())
+            }
+            Command::FlashWrite => {
+                let range = flash_region(self.model, cdb, data.len())?;
+                state.flash[range].copy_from_slice(data);
+                Ok(())
+            }
+            _ => Err(unsupported_command()),
+        }
+    }
+
+    fn transfer_from_device(&mut self, cdb: &[u8], data: &mut [u8]) -> Result<(), Error> {
+        debug!(
+            "sim: transfer_from_device {:02x?}, {} bytes",
+            cdb,
+            data.len()
+        );
+        let command = command(cdb)?;
+
+        let state = self.state();
+        match command {
+            Command::ConfigRead if cdb[1] == 0x50 && data.len() == CONFIG_SIZE => {
+                data.copy_from_slice(&state.config);
+                Ok(())
+            }
+            Command::FlashRead => {
+                let range = flash_region(self.model, cdb, data.len())?;
+                data.copy_from_slice(&state.flash[range]);
+                Ok(())
+            }
+            Command::Read => {
+                let addr = xdata_addr(cdb)?;
+                let len = cdb[1] as usize;
+
+                // the chip only ever sends as many bytes as the CDB asked for
+                if len != data.len() {
+                    return Err(Error::CSWResidue(data.len().abs_diff(len) as u32));
+                }
+                if addr + len > XDATA_SIZE as usize {
+                    return Err(invalid_field());
+                }
+
+                data.copy_from_slice(&state.xdata[addr..addr + len]);
+                Ok(())
+            }
+            _ => Err(unsupported_command()),
+        }
+    }
+
+    fn transfer_reset(&mut self, cdb: &[u8]) -> Result<(), Error> {
+        debug!("sim: transfer_reset {:02x?}", cdb);
+        let command = command(cdb)?;
+
+        let mut state = self.state();
+        match command {
+            Command::Reload => {
+                state.reloads += 1;
+                state.boot();
+                Ok(())
+            }
+            _ => Err(unsupported_command()),
+        }
+    }
+
+    fn transport(&self) -> String {
+        String::from("simulator")
+    }
+}
@@ -0,0 +1,406 @@
+/*
+ *  asm2x6xtool - configuration and firmware management for ASM2x6x chips
+ *  Copyright (C) 2024 Sven Peter <sven@svenpeter.dev>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Recording and deterministic replay of backend transfers.
+//!
+//! A trace is a text file with a header line, a model line and one line per transfer:
+//!
+//! ```text
+//! asm2x6x-trace 1
+//! model ASM2464PD
+//! from e4065007f000 - 240115000100 ok
+//! none e5ab50123400 - - err CSW I/O error: 1
+//! from e4065007f000 - 000000000000 transient USB error: Operation timed out
+//! ```
+//!
+//! Each transfer line holds the direction, the CDB, the data sent to the device, the
+//! data received from the device and the result. `-` marks an empty field. Errors are
+//! marked `transient` if retrying the command may succeed, so that a replay retries
+//! exactly where the recorded run did.
+
+use crate::asm2x6x::policy::TransferPolicy;
+use crate::asm2x6x::{Backend, Info, Model};
+use crate::error::Error;
+use log::{debug, error};
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const MAGIC: &str = "asm2x6x-trace 1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    None,
+    ToDevice,
+    FromDevice,
+    Reset,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Direction::None => "none",
+            Direction::ToDevice => "to",
+            Direction::FromDevice => "from",
+            Direction::Reset => "reset",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "none" => Some(Direction::None),
+            "to" => Some(Direction::ToDevice),
+            "from" => Some(Direction::FromDevice),
+            "reset" => Some(Direction::Reset),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    direction: Direction,
+    cdb: Vec<u8>,
+    data_out: Vec<u8>,
+    data_in: Vec<u8>,
+    /// None on success, otherwise the error message
+    error: Option<String>,
+    /// whether the error was transient, see `Error::is_transient`
+    transient: bool,
+}
+
+fn encode_hex(bfr: &[u8]) -> String {
+    if bfr.is_empty() {
+        return "-".into();
+    }
+
+    bfr.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s == "-" {
+        return Some(Vec::new());
+    }
+
+    s.as_bytes()
+        .chunks(2)
+        .map(|digits| {
+            let digits = std::str::from_utf8(digits).ok().filter(|d| d.len() == 2)?;
+            u8::from_str_radix(digits, 16).ok()
+        })
+        .collect()
+}
+
+impl Display for Entry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} ",
+            self.direction.as_str(),
+            encode_hex(&self.cdb),
+            encode_hex(&self.data_out),
+            encode_hex(&self.data_in)
+        )?;
+
+        match &self.error {
+            None => write!(f, "ok"),
+            Some(err) if self.transient => write!(f, "transient {}", err.replace('\n', " ")),
+            Some(err) => write!(f, "err {}", err.replace('\n', " ")),
+        }
+    }
+}
+
+impl Entry {
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(5, ' ');
+
+        let direction = Direction::parse(fields.next()?)?;
+        let cdb = decode_hex(fields.next()?)?;
+        let data_out = decode_hex(fields.next()?)?;
+        let data_in = decode_hex(fields.next()?)?;
+        let (error, transient) = match fields.next()? {
+            "ok" => (None, false),
+            result => match result.strip_prefix("transient ") {
+                Some(err) => (Some(err.to_string()), true),
+                None => (Some(result.strip_prefix("err ")?.to_string()), false),
+            },
+        };
+
+        Some(Entry {
+            direction,
+            cdb,
+            data_out,
+            data_in,
+            error,
+            transient,
+        })
+    }
+}
+
+/// Trace output that can be handed to several recorders, e.g. to the recorder of a device
+/// that is opened again after a reload, so that all of them append to the same trace.
+#[derive(Clone)]
+pub struct SharedWriter(Arc<Mutex<Box<dyn Write + Send>>>);
+
+impl SharedWriter {
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        SharedWriter(Arc::new(Mutex::new(out)))
+    }
+}
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.0.lock().unwrap().write_all(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+/// Wraps another backend and writes every transfer to a trace.
+pub struct Recorder {
+    backend: Box<dyn Backend>,
+    out: Box<dyn Write + Send>,
+}
+
+impl Recorder {
+    pub fn new(backend: Box<dyn Backend>, mut out: Box<dyn Write + Send>) -> Result<Self, Error> {
+        writeln!(out, "{}", MAGIC)?;
+        writeln!(out, "model {}", backend.model())?;
+        out.flush()?;
+
+        Ok(Recorder { backend, out })
+    }
+
+    /// Continues a trace that an earlier recorder has started, without writing the header
+    /// again.
+    pub fn resume(backend: Box<dyn Backend>, out: Box<dyn Write + Send>) -> Self {
+        Recorder { backend, out }
+    }
+
+    /// A trace that can not be written must not change the outcome of the transfer, so
+    /// failures are only logged.
+    fn record(
+        &mut self,
+        direction: Direction,
+        cdb: &[u8],
+        data_out: &[u8],
+        data_in: &[u8],
+        result: &Result<(), Error>,
+    ) {
+        let entry = Entry {
+            direction,
+            cdb: cdb.to_vec(),
+            data_out: data_out.to_vec(),
+            data_in: data_in.to_vec(),
+            error: result.as_ref().err().map(|err| err.to_string()),
+            transient: result.as_ref().is_err_and(Error::is_transient),
+        };
+
+        // one write per entry so that entries of recorders sharing a writer do not mix, and
+        // flush after every transfer so that the trace survives a crash
+        let written = self
+            .out
+            .write_all(format!("{}\n", entry).as_bytes())
+            .and_then(|_| self.out.flush());
+        if let Err(err) = written {
+            error!("failed to record transfer: {}", err);
+        }
+    }
+}
+
+impl Backend for Recorder {
+    fn model(&self) -> Model {
+        self.backend.model()
+    }
+
+    fn transfer(&mut self, cdb: &[u8]) -> Result<(), Error> {
+        let result = self.backend.transfer(cdb);
+        self.record(Direction::None, cdb, &[], &[], &result);
+        result
+    }
+
+    fn transfer_to_device(&mut self, cdb: &[u8], data: &[u8]) -> Result<(), Error> {
+        let result = self.backend.transfer_to_device(cdb, data);
+        self.record(Direction::ToDevice, cdb, data, &[], &result);
+        result
+    }
+
+    fn transfer_from_device(&mut self, cdb: &[u8], data: &mut [u8]) -> Result<(), Error> {
+        let result = self.backend.transfer_from_device(cdb, data);
+        self.record(Direction::FromDevice, cdb, &[], data, &result);
+        result
+    }
+
+    fn transfer_reset(&mut self, cdb: &[u8]) -> Result<(), Error> {
+        let result = self.backend.transfer_reset(cdb);
+        self.record(Direction::Reset, cdb, &[], &[], &result);
+        result
+    }
+
+    fn set_policy(&mut self, policy: &TransferPolicy) {
+        self.backend.set_policy(policy);
+    }
+
+    fn transport(&self) -> String {
+        self.backend.transport()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub path: PathBuf,
+    pub model: Model,
+}
+
+impl DeviceInfo {
+    /// Reads only the trace header to find out which model it was recorded from.
+    pub fn new(path: &Path) -> Result<Self, Error> {
+        let (model, _) = read_trace(path, true)?;
+
+        Ok(DeviceInfo {
+            path: path.to_path_buf(),
+            model,
+        })
+    }
+}
+
+impl Display for DeviceInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "replay:{}", self.path.display())
+    }
+}
+
+impl Info for DeviceInfo {
+    fn open(&self) -> Result<Box<dyn Backend>, Error> {
+        let (model, entries) = read_trace(&self.path, false)?;
+
+        Ok(Box::new(Replay {
+            model,
+            entries: entries.into_iter(),
+            position: 0,
+        }))
+    }
+
+    fn model(&self) -> Model {
+        self.model
+    }
+}
+
+fn read_trace(path: &Path, header_only: bool) -> Result<(Model, Vec<Entry>), Error> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+
+    if lines.next().transpose()?.as_deref() != Some(MAGIC) {
+        return Err(Error::InvalidTrace(1));
+    }
+
+    let model = lines
+        .next()
+        .transpose()?
+        .and_then(|line| line.strip_prefix("model ")?.parse().ok())
+        .ok_or(Error::InvalidTrace(2))?;
+
+    let mut entries = Vec::new();
+    if !header_only {
+        for (i, line) in lines.enumerate() {
+            entries.push(Entry::parse(&line?).ok_or(Error::InvalidTrace(i + 3))?);
+        }
+    }
+
+    Ok((model, entries))
+}
+
+/// Plays back a trace. Every transfer has to match the next recorded one exactly,
+/// otherwise `Error::TraceMismatch` is returned.
+pub struct Replay {
+    model: Model,
+    entries: std::vec::IntoIter<Entry>,
+    position: usize,
+}
+
+impl Replay {
+    fn next(
+        &mut self,
+        direction: Direction,
+        cdb: &[u8],
+        data_out: &[u8],
+        data_in_len: usize,
+    ) -> Result<Entry, Error> {
+        self.position += 1;
+        let entry = self
+            .entries
+            .next()
+            .ok_or(Error::TraceMismatch(self.position))?;
+
+        debug!("replay: {}", entry);
+
+        if entry.direction != direction
+            || entry.cdb != cdb
+            || entry.data_out != data_out
+            || (direction == Direction::FromDevice && entry.data_in.len() != data_in_len)
+        {
+            return Err(Error::TraceMismatch(self.position));
+        }
+
+        Ok(entry)
+    }
+}
+
+fn replay_result(entry: Entry) -> Result<(), Error> {
+    match entry.error {
+        None => Ok(()),
+        Some(message) => Err(Error::Replayed {
+            message,
+            transient: entry.transient,
+        }),
+    }
+}
+
+impl Backend for Replay {
+    fn model(&self) -> Model {
+        self.model
+    }
+
+    fn transfer(&mut self, cdb: &[u8]) -> Result<(), Error> {
+        replay_result(self.next(Direction::None, cdb, &[], 0)?)
+    }
+
+    fn transfer_to_device(&mut self, cdb: &[u8], data: &[u8]) -> Result<(), Error> {
+        replay_result(self.next(Direction::ToDevice, cdb, data, 0)?)
+    }
+
+    fn transfer_from_device(&mut self, cdb: &[u8], data: &mut [u8]) -> Result<(), Error> {
+        let entry = self.next(Direction::FromDevice, cdb, &[], data.len())?;
+        data.copy_from_slice(&entry.data_in);
+        replay_result(entry)
+    }
+
+    fn transfer_reset(&mut self, cdb: &[u8]) -> Result<(), Error> {
+        replay_result(self.next(Direction::Reset, cdb, &[], 0)?)
+    }
+
+    fn transport(&self) -> String {
+        String::from("trace replay")
+    }
+}
@@ -15,18 +15,52 @@
  *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use crate::asm2x6x::{Backend, Info, Model};
+mod bot;
+mod uas;
+
+use crate::asm2x6x::policy::TransferPolicy;
+use crate::asm2x6x::{Backend, DeviceDetails, Info, Model};
 use crate::error::Error;
 use log::{debug, error, info};
 use rusb::UsbContext;
-use std::string::ToString;
+use std::fmt::{Display, Formatter};
+
+const MASS_STORAGE_CLASS: u8 = 0x08;
+const SCSI_SUBCLASS: u8 = 0x06;
+
+/// Finds the first descriptor of `descriptor_type` in the class and vendor specific
+/// descriptors that follow an interface or endpoint descriptor. They are walked by their
+/// length since others, e.g. the SuperSpeed Endpoint Companion, may come first.
+pub fn find_extra_descriptor(extra: &[u8], descriptor_type: u8) -> Option<&[u8]> {
+    let mut rest = extra;
+
+    while let [len, ty, ..] = *rest {
+        let len = len as usize;
+        if len < 2 || len > rest.len() {
+            debug!("malformed descriptor in {:02x?}", extra);
+            return None;
+        }
+        if ty == descriptor_type {
+            return Some(&rest[..len]);
+        }
+        rest = &rest[len..];
+    }
+
+    None
+}
 
-const ASMEDIA_VID: u16 = 0x174c;
-const CBW_SIGNATURE: u32 = 0x43425355;
-const CSW_SIGNATURE: u32 = 0x53425355;
-const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+/// Data phase of a command, shared by both transports.
+enum Data<'a> {
+    None,
+    ToDevice(&'a [u8]),
+    FromDevice(&'a mut [u8]),
+}
 
-const USBSTORAGE_RESET_REQUEST: u8 = 0xff;
+#[derive(Debug)]
+enum Transport {
+    Bot(bot::Bot),
+    Uas(uas::Uas),
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DeviceInfo {
@@ -36,128 +70,148 @@ pub struct DeviceInfo {
     pub model: Model,
 }
 
-impl ToString for DeviceInfo {
-    fn to_string(&self) -> String {
-        format!("usb:{:03}:{:03}", self.usb_bus, self.usb_addr,)
+impl Display for DeviceInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "usb:{:03}:{:03}", self.usb_bus, self.usb_addr)
     }
 }
 
-impl Info for DeviceInfo {
-    fn open(&self) -> Result<Box<dyn Backend>, Error> {
-        let mut handle = self.device.open()?;
-
-        if handle.kernel_driver_active(0)? {
+impl DeviceInfo {
+    fn claim(
+        &self,
+        handle: &mut rusb::DeviceHandle<rusb::Context>,
+        interface: u8,
+    ) -> Result<(), Error> {
+        if handle.kernel_driver_active(interface)? {
             info!("detaching kernel driver from {:?}", self);
-            if let Err(err) = handle.detach_kernel_driver(0) {
+            if let Err(err) = handle.detach_kernel_driver(interface) {
                 error!("failed to detach kernel driver from {:?}: {}", self, err);
                 return Err(Error::USB(err));
             }
         }
 
-        debug!("claiming interface 0 on {:?}", self);
-        handle.claim_interface(0)?;
-
-        debug!("resetting usb storage interface");
-        handle.write_control(
-            rusb::request_type(
-                rusb::Direction::Out,
-                rusb::RequestType::Class,
-                rusb::Recipient::Interface,
-            ),
-            USBSTORAGE_RESET_REQUEST,
-            0,
-            0,
-            &[],
-            TIMEOUT,
-        )?;
-        std::thread::sleep(std::time::Duration::from_micros(10000));
-        handle.clear_halt(0x02)?;
-        std::thread::sleep(std::time::Duration::from_micros(10000));
-        handle.clear_halt(0x81)?;
-        std::thread::sleep(std::time::Duration::from_micros(10000));
+        debug!("claiming interface {} on {:?}", interface, self);
+        handle.claim_interface(interface)?;
+        Ok(())
+    }
+
+    fn open_bot(
+        &self,
+        setting: bot::Setting,
+        policy: &TransferPolicy,
+    ) -> Result<Box<dyn Backend>, Error> {
+        let mut handle = self.device.open()?;
+
+        self.claim(&mut handle, setting.interface)?;
+        if setting.alt_setting != 0 {
+            handle.set_alternate_setting(setting.interface, setting.alt_setting)?;
+        }
+
+        debug!(
+            "using BOT with endpoints {:#04x}/{:#04x}",
+            setting.bulk_out, setting.bulk_in
+        );
+        let bot = bot::Bot::new(&mut handle, setting, policy.register_timeout)?;
 
         debug!("device successfully initialized");
         Ok(Box::new(Device {
             info: self.clone(),
             handle,
-            tag: 0xdeadbeef,
-            pending: false,
+            policy: *policy,
+            transport: Transport::Bot(bot),
         }))
     }
 
-    fn model(&self) -> Model {
-        self.model
-    }
-}
+    fn open_uas(
+        &self,
+        setting: uas::Setting,
+        policy: &TransferPolicy,
+    ) -> Result<Box<dyn Backend>, Error> {
+        let mut handle = self.device.open()?;
 
-#[derive(Debug, PartialEq, Eq)]
-pub struct Device {
-    info: DeviceInfo,
-    handle: rusb::DeviceHandle<rusb::Context>,
-    tag: u32,
-    pending: bool,
-}
+        self.claim(&mut handle, setting.interface)?;
+        handle.set_alternate_setting(setting.interface, setting.alt_setting)?;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Devices(Vec<DeviceInfo>);
+        debug!("using UAS with pipes {:?}", setting.pipes);
+        let uas = uas::Uas::new(&handle, setting.pipes)?;
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-enum CBWDirection {
-    ToDevice = 0x00,
-    ToHost = 0x80,
+        debug!("device successfully initialized");
+        Ok(Box::new(Device {
+            info: self.clone(),
+            handle,
+            policy: *policy,
+            transport: Transport::Uas(uas),
+        }))
+    }
 }
 
-#[derive(Debug, Copy, Clone)]
-#[allow(clippy::upper_case_acronyms)]
-struct CBW {
-    tag: u32,
-    length: u32,
-    direction: CBWDirection,
-    lun: u8,
-    command_length: u8,
-    command_data: [u8; 16],
-}
+impl Info for DeviceInfo {
+    fn open(&self) -> Result<Box<dyn Backend>, Error> {
+        self.open_with_policy(&TransferPolicy::default())
+    }
 
-impl From<CBW> for [u8; 31] {
-    fn from(cbw: CBW) -> Self {
-        let mut bfr = [0_u8; 31];
+    /// BOT reset recovery already talks to the device, so the policy has to be known
+    /// before the transport is set up.
+    fn open_with_policy(&self, policy: &TransferPolicy) -> Result<Box<dyn Backend>, Error> {
+        let config = self.device.active_config_descriptor()?;
 
-        bfr[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
-        bfr[4..8].copy_from_slice(&cbw.tag.to_le_bytes());
-        bfr[8..12].copy_from_slice(&cbw.length.to_le_bytes());
-        bfr[12] = cbw.direction as u8;
-        bfr[13] = cbw.lun;
-        bfr[14] = cbw.command_length;
-        bfr[15..31].copy_from_slice(&cbw.command_data);
+        if let Some(setting) = uas::find_setting(&config, MASS_STORAGE_CLASS, SCSI_SUBCLASS) {
+            return self.open_uas(setting, policy);
+        }
+        if let Some(setting) = bot::find_setting(&config, MASS_STORAGE_CLASS, SCSI_SUBCLASS) {
+            return self.open_bot(setting, policy);
+        }
 
-        bfr
+        Err(Error::NoMassStorageInterface)
     }
-}
 
-#[derive(Debug, Copy, Clone)]
-#[allow(clippy::upper_case_acronyms)]
-struct CSW {
-    tag: u32,
-    residue: u32,
-    status: u8,
-}
+    fn model(&self) -> Model {
+        self.model
+    }
 
-impl TryFrom<&[u8; 13]> for CSW {
-    type Error = Error;
-
-    fn try_from(bfr: &[u8; 13]) -> Result<Self, Self::Error> {
-        if u32::from_le_bytes([bfr[0], bfr[1], bfr[2], bfr[3]]) != CSW_SIGNATURE {
-            Err(Error::InvalidCSW)
-        } else {
-            Ok(CSW {
-                tag: u32::from_le_bytes([bfr[4], bfr[5], bfr[6], bfr[7]]),
-                residue: u32::from_le_bytes([bfr[8], bfr[9], bfr[10], bfr[11]]),
-                status: bfr[12],
-            })
+    /// The serial number is a string descriptor which can only be read after opening
+    /// the device and is left out here.
+    fn details(&self) -> DeviceDetails {
+        let usb_id = self
+            .device
+            .device_descriptor()
+            .ok()
+            .map(|desc| (desc.vendor_id(), desc.product_id()));
+
+        let port_path = self.device.port_numbers().ok().map(|ports| {
+            let ports: Vec<String> = ports.iter().map(|port| port.to_string()).collect();
+            format!("{}-{}", self.usb_bus, ports.join("."))
+        });
+
+        let speed = match self.device.speed() {
+            rusb::Speed::Low => Some("1.5"),
+            rusb::Speed::Full => Some("12"),
+            rusb::Speed::High => Some("480"),
+            rusb::Speed::Super => Some("5000"),
+            rusb::Speed::SuperPlus => Some("10000"),
+            _ => None,
+        };
+
+        DeviceDetails {
+            usb_id,
+            port_path,
+            speed: speed.map(str::to_string),
+            ..Default::default()
         }
     }
 }
 
+#[derive(Debug)]
+pub struct Device {
+    info: DeviceInfo,
+    handle: rusb::DeviceHandle<rusb::Context>,
+    policy: TransferPolicy,
+    transport: Transport,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Devices(Vec<DeviceInfo>);
+
 pub fn find_devices(devices: &mut Vec<Box<dyn Info>>) -> Result<(), Error> {
     let rusb_devices = rusb::Context::new()?.devices()?;
 
@@ -176,17 +230,14 @@ pub fn find_devices(devices: &mut Vec<Box<dyn Info>>) -> Result<(), Error> {
             pid
         );
 
-        if vid != ASMEDIA_VID {
-            continue;
-        }
-
-        if pid != 0x2463 {
-            continue;
-        }
+        let model = match Model::from_usb_id(vid, pid) {
+            Some(model) => model,
+            None => continue,
+        };
 
         devices.push(Box::new(DeviceInfo {
             device: dev,
-            model: Model::ASM2464PD,
+            model,
             usb_bus,
             usb_addr,
         }));
@@ -196,61 +247,13 @@ pub fn find_devices(devices: &mut Vec<Box<dyn Info>>) -> Result<(), Error> {
 }
 
 impl Device {
-    fn send_cbw(&mut self, cdb: &[u8], direction: CBWDirection, length: u32) -> Result<(), Error> {
-        if cdb.len() > 16 {
-            return Err(Error::InvalidCDB);
-        }
-
-        if self.pending {
-            return Err(Error::TransferStillPending);
-        }
-
-        let mut command_data = [0_u8; 16];
-        command_data[..cdb.len()].copy_from_slice(cdb);
-
-        self.tag += 1;
-        let cbw: CBW = CBW {
-            tag: self.tag,
-            length,
-            direction,
-            lun: 0x00,
-            command_length: cdb.len() as u8,
-            command_data,
-        };
-
-        let bfr = <[u8; 31]>::from(cbw);
-        debug!("Sending CBW: {:?}", bfr);
+    fn submit(&mut self, cdb: &[u8], data: Data) -> Result<(), Error> {
+        let timeout = self.policy.timeout_for(cdb);
 
-        self.handle.write_bulk(0x02, &bfr, TIMEOUT)?;
-        self.pending = true;
-
-        debug!("CBW sent successfully");
-        Ok(())
-    }
-
-    fn recv_csw(&mut self) -> Result<(), Error> {
-        if !self.pending {
-            return Err(Error::NoTransferPending);
-        }
-
-        debug!("trying to read CSW");
-        let mut bfr = [0_u8; 13];
-        self.handle.read_bulk(0x81, &mut bfr, TIMEOUT)?;
-        debug!("CSW: {:?}", bfr);
-
-        let csw = CSW::try_from(&bfr)?;
-        if csw.status != 0x00 {
-            return Err(Error::CSWIOError(csw.status));
-        }
-        if csw.tag != self.tag {
-            return Err(Error::InvalidCSWTag);
-        }
-        if csw.residue != 0 {
-            return Err(Error::CSWResidue(csw.residue));
+        match &mut self.transport {
+            Transport::Bot(bot) => bot.transfer(&mut self.handle, cdb, data, timeout),
+            Transport::Uas(uas) => uas.transfer(&mut self.handle, cdb, data, timeout),
         }
-
-        self.pending = false;
-        Ok(())
     }
 }
 
@@ -260,28 +263,38 @@ impl Backend for Device {
     }
 
     fn transfer(&mut self, cdb: &[u8]) -> Result<(), Error> {
-        self.send_cbw(cdb, CBWDirection::ToDevice, 0)?;
-        self.recv_csw()?;
-        Ok(())
+        self.submit(cdb, Data::None)
     }
 
     fn transfer_to_device(&mut self, cdb: &[u8], data: &[u8]) -> Result<(), Error> {
-        self.send_cbw(cdb, CBWDirection::ToDevice, data.len() as u32)?;
-
-        debug!("trying to send {} bytes to device", data.len());
-        self.handle.write_bulk(0x02, data, TIMEOUT)?;
-
-        self.recv_csw()?;
-        Ok(())
+        self.submit(cdb, Data::ToDevice(data))
     }
 
     fn transfer_from_device(&mut self, cdb: &[u8], data: &mut [u8]) -> Result<(), Error> {
-        self.send_cbw(cdb, CBWDirection::ToHost, data.len() as u32)?;
+        self.submit(cdb, Data::FromDevice(data))
+    }
 
-        debug!("trying to read {} bytes from device", data.len());
-        self.handle.read_bulk(0x81, data, TIMEOUT)?;
+    fn transfer_reset(&mut self, cdb: &[u8]) -> Result<(), Error> {
+        match self.submit(cdb, Data::None) {
+            // same as the kernel backend: only the device going away counts, a stall or
+            // timeout means the reload did not happen
+            Err(Error::USB(err @ (rusb::Error::NoDevice | rusb::Error::Io))) => {
+                debug!("device disconnected after reset: {}", err);
+                Ok(())
+            }
+            res => res,
+        }
+    }
 
-        self.recv_csw()?;
-        Ok(())
+    fn set_policy(&mut self, policy: &TransferPolicy) {
+        self.policy = *policy;
+    }
+
+    fn transport(&self) -> String {
+        match self.transport {
+            Transport::Bot(_) => "USB bulk-only (libusb)",
+            Transport::Uas(_) => "USB UAS (libusb)",
+        }
+        .to_string()
     }
 }
@@ -0,0 +1,401 @@
+/*
+ *  asm2x6xtool - configuration and firmware management for ASM2x6x chips
+ *  Copyright (C) 2024 Sven Peter <sven@svenpeter.dev>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! USB Mass Storage Bulk-Only Transport.
+//!
+//! Every command is a Command Block Wrapper on the bulk OUT endpoint, followed by an
+//! optional data phase and a Command Status Wrapper on the bulk IN endpoint.
+
+use super::Data;
+use crate::error::Error;
+use crate::scsi::{Sense, REQUEST_SENSE, SENSE_BUFFER_SIZE};
+use log::{debug, warn};
+use std::time::Duration;
+
+pub const BOT_PROTOCOL: u8 = 0x50;
+
+const CBW_SIGNATURE: u32 = 0x43425355;
+const CSW_SIGNATURE: u32 = 0x53425355;
+
+const USBSTORAGE_RESET_REQUEST: u8 = 0xff;
+
+const CSW_STATUS_FAILED: u8 = 0x01;
+
+/// Interface, alternate setting and bulk endpoints that implement Bulk-Only Transport.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Setting {
+    pub interface: u8,
+    pub alt_setting: u8,
+    pub bulk_in: u8,
+    pub bulk_out: u8,
+}
+
+#[derive(Debug)]
+pub struct Bot {
+    setting: Setting,
+    tag: u32,
+    pending: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum CBWDirection {
+    ToDevice = 0x00,
+    ToHost = 0x80,
+}
+
+#[derive(Debug, Copy, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+struct CBW {
+    tag: u32,
+    length: u32,
+    direction: CBWDirection,
+    lun: u8,
+    command_length: u8,
+    command_data: [u8; 16],
+}
+
+impl From<CBW> for [u8; 31] {
+    fn from(cbw: CBW) -> Self {
+        let mut bfr = [0_u8; 31];
+
+        bfr[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        bfr[4..8].copy_from_slice(&cbw.tag.to_le_bytes());
+        bfr[8..12].copy_from_slice(&cbw.length.to_le_bytes());
+        bfr[12] = cbw.direction as u8;
+        bfr[13] = cbw.lun;
+        bfr[14] = cbw.command_length;
+        bfr[15..31].copy_from_slice(&cbw.command_data);
+
+        bfr
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+struct CSW {
+    tag: u32,
+    residue: u32,
+    status: u8,
+}
+
+impl TryFrom<&[u8; 13]> for CSW {
+    type Error = Error;
+
+    fn try_from(bfr: &[u8; 13]) -> Result<Self, Self::Error> {
+        if u32::from_le_bytes([bfr[0], bfr[1], bfr[2], bfr[3]]) != CSW_SIGNATURE {
+            Err(Error::InvalidCSW)
+        } else {
+            Ok(CSW {
+                tag: u32::from_le_bytes([bfr[4], bfr[5], bfr[6], bfr[7]]),
+                residue: u32::from_le_bytes([bfr[8], bfr[9], bfr[10], bfr[11]]),
+                status: bfr[12],
+            })
+        }
+    }
+}
+
+/// Looks for a mass storage alternate setting speaking Bulk-Only Transport that has
+/// one bulk endpoint in each direction.
+pub fn find_setting(config: &rusb::ConfigDescriptor, class: u8, subclass: u8) -> Option<Setting> {
+    for interface in config.interfaces() {
+        for desc in interface.descriptors() {
+            if desc.class_code() != class
+                || desc.sub_class_code() != subclass
+                || desc.protocol_code() != BOT_PROTOCOL
+            {
+                continue;
+            }
+
+            let mut bulk_in = None;
+            let mut bulk_out = None;
+
+            for endpoint in desc.endpoint_descriptors() {
+                if endpoint.transfer_type() != rusb::TransferType::Bulk {
+                    continue;
+                }
+
+                match endpoint.direction() {
+                    rusb::Direction::In => bulk_in = bulk_in.or(Some(endpoint.address())),
+                    rusb::Direction::Out => bulk_out = bulk_out.or(Some(endpoint.address())),
+                }
+            }
+
+            if let (Some(bulk_in), Some(bulk_out)) = (bulk_in, bulk_out) {
+                return Some(Setting {
+                    interface: desc.interface_number(),
+                    alt_setting: desc.setting_number(),
+                    bulk_in,
+                    bulk_out,
+                });
+            }
+
+            debug!(
+                "interface {} alt setting {} is missing bulk endpoints",
+                desc.interface_number(),
+                desc.setting_number()
+            );
+        }
+    }
+
+    None
+}
+
+impl Bot {
+    /// Brings the interface into a known state with a reset recovery.
+    pub fn new(
+        handle: &mut rusb::DeviceHandle<rusb::Context>,
+        setting: Setting,
+        timeout: Duration,
+    ) -> Result<Self, Error> {
+        let mut bot = Bot {
+            setting,
+            tag: 0xdeadbeef,
+            pending: false,
+        };
+
+        bot.reset_recovery(handle, timeout)?;
+        Ok(bot)
+    }
+
+    /// Bulk-Only Mass Storage Reset followed by clearing the halt condition on both
+    /// endpoints, which is required after a phase error or an invalid CSW.
+    fn reset_recovery(
+        &mut self,
+        handle: &mut rusb::DeviceHandle<rusb::Context>,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        // whatever was in flight is gone after the reset, even if it fails halfway
+        self.pending = false;
+
+        debug!("resetting usb storage interface");
+        handle.write_control(
+            rusb::request_type(
+                rusb::Direction::Out,
+                rusb::RequestType::Class,
+                rusb::Recipient::Interface,
+            ),
+            USBSTORAGE_RESET_REQUEST,
+            0,
+            self.setting.interface as u16,
+            &[],
+            timeout,
+        )?;
+        std::thread::sleep(std::time::Duration::from_micros(10000));
+        handle.clear_halt(self.setting.bulk_out)?;
+        std::thread::sleep(std::time::Duration::from_micros(10000));
+        handle.clear_halt(self.setting.bulk_in)?;
+        std::thread::sleep(std::time::Duration::from_micros(10000));
+
+        Ok(())
+    }
+
+    fn send_cbw(
+        &mut self,
+        handle: &rusb::DeviceHandle<rusb::Context>,
+        cdb: &[u8],
+        direction: CBWDirection,
+        length: u32,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        if cdb.len() > 16 {
+            return Err(Error::InvalidCDB);
+        }
+
+        if self.pending {
+            return Err(Error::TransferStillPending);
+        }
+
+        let mut command_data = [0_u8; 16];
+        command_data[..cdb.len()].copy_from_slice(cdb);
+
+        self.tag = self.tag.wrapping_add(1);
+        let cbw: CBW = CBW {
+            tag: self.tag,
+            length,
+            direction,
+            lun: 0x00,
+            command_length: cdb.len() as u8,
+            command_data,
+        };
+
+        let bfr = <[u8; 31]>::from(cbw);
+        debug!("Sending CBW: {:?}", bfr);
+
+        // the CBW counts as in flight as soon as we start sending it so that a failure
+        // here also triggers a reset recovery
+        self.pending = true;
+        handle.write_bulk(self.setting.bulk_out, &bfr, timeout)?;
+
+        debug!("CBW sent successfully");
+        Ok(())
+    }
+
+    /// Reads the CSW. `pending` is only cleared once a valid CSW for the current
+    /// command has been received; every other failure needs a reset recovery.
+    fn recv_csw(
+        &mut self,
+        handle: &mut rusb::DeviceHandle<rusb::Context>,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        if !self.pending {
+            return Err(Error::NoTransferPending);
+        }
+
+        debug!("trying to read CSW");
+        let mut bfr = [0_u8; 13];
+        match handle.read_bulk(self.setting.bulk_in, &mut bfr, timeout) {
+            // a stalled bulk IN endpoint has to be cleared and the CSW read again
+            Err(rusb::Error::Pipe) => {
+                debug!("CSW read stalled, clearing halt and retrying");
+                handle.clear_halt(self.setting.bulk_in)?;
+                handle.read_bulk(self.setting.bulk_in, &mut bfr, timeout)?;
+            }
+            res => {
+                res?;
+            }
+        }
+        debug!("CSW: {:?}", bfr);
+
+        let csw = CSW::try_from(&bfr)?;
+        if csw.tag != self.tag {
+            return Err(Error::InvalidCSWTag);
+        }
+
+        match csw.status {
+            0x00 => (),
+            0x02 => return Err(Error::CSWPhaseError),
+            status => {
+                self.pending = false;
+                return Err(Error::CSWIOError(status));
+            }
+        }
+
+        self.pending = false;
+        if csw.residue != 0 {
+            return Err(Error::CSWResidue(csw.residue));
+        }
+
+        Ok(())
+    }
+
+    fn command(
+        &mut self,
+        handle: &mut rusb::DeviceHandle<rusb::Context>,
+        cdb: &[u8],
+        data: &mut Data,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let (endpoint, res) = match data {
+            Data::None => {
+                self.send_cbw(handle, cdb, CBWDirection::ToDevice, 0, timeout)?;
+                (self.setting.bulk_out, Ok(0))
+            }
+            Data::ToDevice(bfr) => {
+                self.send_cbw(
+                    handle,
+                    cdb,
+                    CBWDirection::ToDevice,
+                    bfr.len() as u32,
+                    timeout,
+                )?;
+
+                debug!("trying to send {} bytes to device", bfr.len());
+                (
+                    self.setting.bulk_out,
+                    handle.write_bulk(self.setting.bulk_out, bfr, timeout),
+                )
+            }
+            Data::FromDevice(bfr) => {
+                self.send_cbw(handle, cdb, CBWDirection::ToHost, bfr.len() as u32, timeout)?;
+
+                debug!("trying to read {} bytes from device", bfr.len());
+                (
+                    self.setting.bulk_in,
+                    handle.read_bulk(self.setting.bulk_in, bfr, timeout),
+                )
+            }
+        };
+
+        match res {
+            // the device stalls the data stage if it has less data than the host asked
+            // for or fails the command; the CSW still follows once the halt is cleared
+            Err(rusb::Error::Pipe) => {
+                debug!("data stage stalled on {:#04x}, clearing halt", endpoint);
+                handle.clear_halt(endpoint)?;
+            }
+            res => {
+                res?;
+            }
+        }
+
+        self.recv_csw(handle, timeout)
+    }
+
+    /// Runs a command and performs a reset recovery if it did not complete cleanly,
+    /// so that the next command starts from a known state.
+    pub fn transfer(
+        &mut self,
+        handle: &mut rusb::DeviceHandle<rusb::Context>,
+        cdb: &[u8],
+        mut data: Data,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let err = match self.command(handle, cdb, &mut data, timeout) {
+            Err(Error::CSWIOError(CSW_STATUS_FAILED)) => {
+                return Err(self.request_sense(handle, timeout));
+            }
+            Err(err) if self.pending => err,
+            res => return res,
+        };
+
+        warn!("transfer failed: {}, performing reset recovery", err);
+        if let Err(recovery_err) = self.reset_recovery(handle, timeout) {
+            debug!("reset recovery failed: {}", recovery_err);
+        }
+
+        Err(err)
+    }
+
+    /// Fetches the sense data describing why the last command failed. Falls back to
+    /// the plain CSW status if the device does not provide any.
+    fn request_sense(
+        &mut self,
+        handle: &mut rusb::DeviceHandle<rusb::Context>,
+        timeout: Duration,
+    ) -> Error {
+        let cdb = [REQUEST_SENSE, 0, 0, 0, SENSE_BUFFER_SIZE as u8, 0];
+        let mut bfr = [0_u8; SENSE_BUFFER_SIZE];
+
+        // devices usually send less than requested, so a residue is expected here
+        match self.command(handle, &cdb, &mut Data::FromDevice(&mut bfr), timeout) {
+            Ok(()) | Err(Error::CSWResidue(_)) => (),
+            Err(err) => {
+                debug!("REQUEST SENSE failed: {}", err);
+                if self.pending {
+                    let _ = self.reset_recovery(handle, timeout);
+                }
+                return Error::CSWIOError(CSW_STATUS_FAILED);
+            }
+        }
+
+        debug!("sense: {:02x?}", bfr);
+        match Sense::parse(&bfr) {
+            Some(sense) => Error::Sense(sense),
+            None => Error::CSWIOError(CSW_STATUS_FAILED),
+        }
+    }
+}
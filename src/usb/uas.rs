@@ -0,0 +1,520 @@
+/*
+ *  asm2x6xtool - configuration and firmware management for ASM2x6x chips
+ *  Copyright (C) 2024 Sven Peter <sven@svenpeter.dev>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! USB Attached SCSI transport.
+//!
+//! Commands are sent as Command IUs on the command pipe and completed by a Sense IU
+//! on the status pipe. On SuperSpeed the status and data pipes use bulk streams with
+//! the stream ID equal to the command tag; on slower links the device announces data
+//! phases with Read Ready / Write Ready IUs on the status pipe instead.
+
+use super::{find_extra_descriptor, Data};
+use crate::error::Error;
+use crate::scsi::Sense;
+use libusb1_sys::constants::*;
+use libusb1_sys::*;
+use log::debug;
+use rusb::UsbContext;
+use std::ffi::{c_int, c_uint, c_void};
+use std::time::Duration;
+
+pub const UAS_PROTOCOL: u8 = 0x62;
+
+const PIPE_USAGE_DESCRIPTOR: u8 = 0x24;
+const PIPE_ID_COMMAND: u8 = 1;
+const PIPE_ID_STATUS: u8 = 2;
+const PIPE_ID_DATA_IN: u8 = 3;
+const PIPE_ID_DATA_OUT: u8 = 4;
+
+const IU_ID_COMMAND: u8 = 0x01;
+const IU_ID_SENSE: u8 = 0x03;
+const IU_ID_RESPONSE: u8 = 0x04;
+const IU_ID_READ_READY: u8 = 0x06;
+const IU_ID_WRITE_READY: u8 = 0x07;
+
+const MAX_STREAMS: u32 = 4;
+const STATUS_BUFFER_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pipes {
+    pub command: u8,
+    pub status: u8,
+    pub data_in: u8,
+    pub data_out: u8,
+}
+
+/// Interface and alternate setting that implement UAS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Setting {
+    pub interface: u8,
+    pub alt_setting: u8,
+    pub pipes: Pipes,
+}
+
+#[derive(Debug)]
+pub struct Uas {
+    pipes: Pipes,
+    streams: bool,
+    /// tags, and with streams the stream IDs, cycle through 1..=tags
+    tags: u16,
+    tag: u16,
+}
+
+/// Looks for a mass storage alternate setting speaking UAS and maps its endpoints
+/// to pipes using the Pipe Usage descriptors following each endpoint descriptor.
+pub fn find_setting(config: &rusb::ConfigDescriptor, class: u8, subclass: u8) -> Option<Setting> {
+    for interface in config.interfaces() {
+        for desc in interface.descriptors() {
+            if desc.class_code() != class
+                || desc.sub_class_code() != subclass
+                || desc.protocol_code() != UAS_PROTOCOL
+            {
+                continue;
+            }
+
+            let mut command = None;
+            let mut status = None;
+            let mut data_in = None;
+            let mut data_out = None;
+
+            for endpoint in desc.endpoint_descriptors() {
+                let extra = endpoint.extra().unwrap_or_default();
+                let pipe_id = match find_extra_descriptor(extra, PIPE_USAGE_DESCRIPTOR) {
+                    Some([4, _, pipe_id, ..]) => *pipe_id,
+                    _ => continue,
+                };
+
+                match pipe_id {
+                    PIPE_ID_COMMAND => command = Some(endpoint.address()),
+                    PIPE_ID_STATUS => status = Some(endpoint.address()),
+                    PIPE_ID_DATA_IN => data_in = Some(endpoint.address()),
+                    PIPE_ID_DATA_OUT => data_out = Some(endpoint.address()),
+                    _ => (),
+                }
+            }
+
+            if let (Some(command), Some(status), Some(data_in), Some(data_out)) =
+                (command, status, data_in, data_out)
+            {
+                return Some(Setting {
+                    interface: desc.interface_number(),
+                    alt_setting: desc.setting_number(),
+                    pipes: Pipes {
+                        command,
+                        status,
+                        data_in,
+                        data_out,
+                    },
+                });
+            }
+
+            debug!(
+                "interface {} alt setting {} is missing UAS pipes",
+                desc.interface_number(),
+                desc.setting_number()
+            );
+        }
+    }
+
+    None
+}
+
+fn libusb_error(err: c_int) -> rusb::Error {
+    match err {
+        LIBUSB_ERROR_IO => rusb::Error::Io,
+        LIBUSB_ERROR_INVALID_PARAM => rusb::Error::InvalidParam,
+        LIBUSB_ERROR_ACCESS => rusb::Error::Access,
+        LIBUSB_ERROR_NO_DEVICE => rusb::Error::NoDevice,
+        LIBUSB_ERROR_NOT_FOUND => rusb::Error::NotFound,
+        LIBUSB_ERROR_BUSY => rusb::Error::Busy,
+        LIBUSB_ERROR_TIMEOUT => rusb::Error::Timeout,
+        LIBUSB_ERROR_OVERFLOW => rusb::Error::Overflow,
+        LIBUSB_ERROR_PIPE => rusb::Error::Pipe,
+        LIBUSB_ERROR_INTERRUPTED => rusb::Error::Interrupted,
+        LIBUSB_ERROR_NO_MEM => rusb::Error::NoMem,
+        LIBUSB_ERROR_NOT_SUPPORTED => rusb::Error::NotSupported,
+        _ => rusb::Error::Other,
+    }
+}
+
+fn check_length(data: &Data, len: usize) -> Result<(), Error> {
+    let expected = match data {
+        Data::None => 0,
+        Data::ToDevice(bfr) => bfr.len(),
+        Data::FromDevice(bfr) => bfr.len(),
+    };
+
+    if len != expected {
+        return Err(Error::ShortTransfer(expected - len.min(expected)));
+    }
+
+    Ok(())
+}
+
+/// Clears the halt of a stalled pipe so that the next command can use it again and
+/// passes the error on.
+fn clear_stall(handle: &mut rusb::DeviceHandle<rusb::Context>, endpoint: u8, err: Error) -> Error {
+    if let Error::USB(rusb::Error::Pipe) = err {
+        debug!("{:#04x} stalled, clearing halt", endpoint);
+        if let Err(clear_err) = handle.clear_halt(endpoint) {
+            debug!("clearing halt on {:#04x} failed: {}", endpoint, clear_err);
+        }
+    }
+
+    err
+}
+
+/// Only a stalled data phase is followed by a status IU, after anything else, e.g. a
+/// timeout, there is nothing left to wait for.
+fn status_follows(transferred: &Result<usize, Error>) -> bool {
+    !matches!(transferred, Err(err) if !matches!(err, Error::USB(rusb::Error::Pipe)))
+}
+
+extern "system" fn transfer_callback(transfer: *mut libusb_transfer) {
+    // SAFETY: user_data always points to the completion flag owned by the StreamTransfer
+    unsafe { *((*transfer).user_data as *mut c_int) = 1 };
+}
+
+/// A single bulk stream transfer submitted through the asynchronous libusb API, which
+/// is the only way to set a stream ID.
+struct StreamTransfer {
+    context: *mut libusb_context,
+    transfer: *mut libusb_transfer,
+    completed: *mut c_int,
+}
+
+impl StreamTransfer {
+    /// # Safety
+    ///
+    /// `buffer` must stay valid for `length` bytes until the transfer is dropped.
+    unsafe fn submit(
+        handle: &rusb::DeviceHandle<rusb::Context>,
+        endpoint: u8,
+        stream_id: u32,
+        buffer: *mut u8,
+        length: usize,
+        timeout: Duration,
+    ) -> Result<Self, Error> {
+        let transfer = libusb_alloc_transfer(0);
+        if transfer.is_null() {
+            return Err(Error::USB(rusb::Error::NoMem));
+        }
+
+        let completed = Box::into_raw(Box::new(0 as c_int));
+        libusb_fill_bulk_stream_transfer(
+            transfer,
+            handle.as_raw(),
+            endpoint,
+            stream_id,
+            buffer,
+            length as c_int,
+            transfer_callback,
+            completed as *mut c_void,
+            timeout.as_millis() as c_uint,
+        );
+
+        let res = libusb_submit_transfer(transfer);
+        if res != 0 {
+            libusb_free_transfer(transfer);
+            drop(Box::from_raw(completed));
+            return Err(Error::USB(libusb_error(res)));
+        }
+
+        Ok(StreamTransfer {
+            context: handle.context().as_raw(),
+            transfer,
+            completed,
+        })
+    }
+
+    fn poll(&mut self) {
+        // SAFETY: completed stays valid until drop and is only written by transfer_callback
+        // from within libusb_handle_events_completed on this thread
+        while unsafe { std::ptr::read_volatile(self.completed) } == 0 {
+            let res = unsafe { libusb_handle_events_completed(self.context, self.completed) };
+            if res < 0 && res != LIBUSB_ERROR_INTERRUPTED {
+                debug!("libusb_handle_events_completed failed: {}", res);
+            }
+        }
+    }
+
+    /// Waits until the transfer has finished and returns the number of bytes transferred.
+    fn wait(&mut self) -> Result<usize, Error> {
+        self.poll();
+
+        // SAFETY: the transfer has completed and libusb no longer touches it
+        let (status, actual_length) =
+            unsafe { ((*self.transfer).status, (*self.transfer).actual_length) };
+
+        match status {
+            LIBUSB_TRANSFER_COMPLETED => Ok(actual_length as usize),
+            LIBUSB_TRANSFER_TIMED_OUT => Err(Error::USB(rusb::Error::Timeout)),
+            LIBUSB_TRANSFER_CANCELLED => Err(Error::USB(rusb::Error::Interrupted)),
+            LIBUSB_TRANSFER_STALL => Err(Error::USB(rusb::Error::Pipe)),
+            LIBUSB_TRANSFER_NO_DEVICE => Err(Error::USB(rusb::Error::NoDevice)),
+            LIBUSB_TRANSFER_OVERFLOW => Err(Error::USB(rusb::Error::Overflow)),
+            _ => Err(Error::USB(rusb::Error::Io)),
+        }
+    }
+}
+
+impl Drop for StreamTransfer {
+    fn drop(&mut self) {
+        // SAFETY: a transfer that is still in flight is cancelled and waited for before
+        // it and its completion flag are freed
+        unsafe {
+            if std::ptr::read_volatile(self.completed) == 0 {
+                libusb_cancel_transfer(self.transfer);
+                self.poll();
+            }
+
+            libusb_free_transfer(self.transfer);
+            drop(Box::from_raw(self.completed));
+        }
+    }
+}
+
+impl Uas {
+    pub fn new(handle: &rusb::DeviceHandle<rusb::Context>, pipes: Pipes) -> Result<Self, Error> {
+        let streams = matches!(
+            handle.device().speed(),
+            rusb::Speed::Super | rusb::Speed::SuperPlus
+        );
+
+        let mut tags = MAX_STREAMS as u16;
+        if streams {
+            let mut endpoints = [pipes.status, pipes.data_in, pipes.data_out];
+
+            // SAFETY: endpoints is a valid array and its length is passed along
+            let res = unsafe {
+                libusb_alloc_streams(
+                    handle.as_raw(),
+                    MAX_STREAMS,
+                    endpoints.as_mut_ptr(),
+                    endpoints.len() as c_int,
+                )
+            };
+            if res < 0 {
+                return Err(Error::USB(libusb_error(res)));
+            }
+            if res == 0 {
+                return Err(Error::USB(rusb::Error::NotSupported));
+            }
+
+            // the host controller may grant fewer streams than asked for
+            debug!("allocated {} UAS streams", res);
+            tags = res as u16;
+        }
+
+        Ok(Uas {
+            pipes,
+            streams,
+            tags,
+            tag: 0,
+        })
+    }
+
+    fn next_tag(&mut self) -> u16 {
+        // stream IDs start at 1 and each command uses the stream matching its tag
+        self.tag = self.tag % self.tags + 1;
+        self.tag
+    }
+
+    fn command_iu(tag: u16, cdb: &[u8]) -> Result<[u8; 32], Error> {
+        if cdb.len() > 16 {
+            return Err(Error::InvalidCDB);
+        }
+
+        let mut iu = [0_u8; 32];
+        iu[0] = IU_ID_COMMAND;
+        iu[2..4].copy_from_slice(&tag.to_be_bytes());
+        // task attribute: simple, LUN: 0
+        iu[16..16 + cdb.len()].copy_from_slice(cdb);
+        Ok(iu)
+    }
+
+    fn check_status(tag: u16, iu: &[u8]) -> Result<(), Error> {
+        debug!("status IU: {:02x?}", iu);
+
+        if iu.len() < 4 || u16::from_be_bytes([iu[2], iu[3]]) != tag {
+            return Err(Error::InvalidUASTag);
+        }
+
+        match iu[0] {
+            IU_ID_SENSE if iu.len() >= 16 => match iu[6] {
+                0x00 => Ok(()),
+                status => {
+                    let len = u16::from_be_bytes([iu[14], iu[15]]) as usize;
+                    let sense = &iu[16..(16 + len).min(iu.len())];
+                    match Sense::parse(sense) {
+                        Some(sense) => Err(Error::Sense(sense)),
+                        None => Err(Error::UASStatus(status)),
+                    }
+                }
+            },
+            IU_ID_RESPONSE if iu.len() >= 8 => Err(Error::UASResponse(iu[7])),
+            _ => Err(Error::InvalidUASIU(iu[0])),
+        }
+    }
+
+    pub fn transfer(
+        &mut self,
+        handle: &mut rusb::DeviceHandle<rusb::Context>,
+        cdb: &[u8],
+        data: Data,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let tag = self.next_tag();
+        let iu = Self::command_iu(tag, cdb)?;
+
+        if self.streams {
+            self.transfer_streams(handle, tag, &iu, data, timeout)
+        } else {
+            self.transfer_ready(handle, tag, &iu, data, timeout)
+        }
+    }
+
+    fn transfer_streams(
+        &mut self,
+        handle: &mut rusb::DeviceHandle<rusb::Context>,
+        tag: u16,
+        iu: &[u8; 32],
+        mut data: Data,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let mut status = [0_u8; STATUS_BUFFER_SIZE];
+        let data_pipe = match &data {
+            Data::ToDevice(_) => self.pipes.data_out,
+            _ => self.pipes.data_in,
+        };
+
+        // the status and data transfers have to be queued before the command is sent
+        // SAFETY: status and data outlive the transfers which are dropped at the end of
+        // this function at the latest
+        let mut status_transfer = unsafe {
+            StreamTransfer::submit(
+                handle,
+                self.pipes.status,
+                tag as u32,
+                status.as_mut_ptr(),
+                status.len(),
+                timeout,
+            )?
+        };
+        let data_transfer = unsafe {
+            match &mut data {
+                Data::None => None,
+                Data::ToDevice(bfr) => Some(StreamTransfer::submit(
+                    handle,
+                    self.pipes.data_out,
+                    tag as u32,
+                    bfr.as_ptr() as *mut u8,
+                    bfr.len(),
+                    timeout,
+                )?),
+                Data::FromDevice(bfr) => Some(StreamTransfer::submit(
+                    handle,
+                    self.pipes.data_in,
+                    tag as u32,
+                    bfr.as_mut_ptr(),
+                    bfr.len(),
+                    timeout,
+                )?),
+            }
+        };
+
+        debug!("sending command IU: {:02x?}", iu);
+        handle.write_bulk(self.pipes.command, iu, timeout)?;
+
+        let transferred = match data_transfer {
+            None => Ok(0),
+            Some(mut data_transfer) => data_transfer
+                .wait()
+                .map_err(|err| clear_stall(handle, data_pipe, err)),
+        };
+        debug!("data phase: {:?}", transferred);
+        if !status_follows(&transferred) {
+            return transferred.map(|_| ());
+        }
+
+        // a failed command usually cuts the data phase short, its sense data is the
+        // more useful error
+        let len = status_transfer
+            .wait()
+            .map_err(|err| clear_stall(handle, self.pipes.status, err))?;
+        Self::check_status(tag, &status[..len])?;
+        check_length(&data, transferred?)
+    }
+
+    fn transfer_ready(
+        &mut self,
+        handle: &mut rusb::DeviceHandle<rusb::Context>,
+        tag: u16,
+        iu: &[u8; 32],
+        mut data: Data,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let mut status = [0_u8; STATUS_BUFFER_SIZE];
+
+        debug!("sending command IU: {:02x?}", iu);
+        handle.write_bulk(self.pipes.command, iu, timeout)?;
+
+        let mut transferred = Ok(0);
+        let ready = match &data {
+            Data::None => None,
+            Data::ToDevice(_) => Some(IU_ID_WRITE_READY),
+            Data::FromDevice(_) => Some(IU_ID_READ_READY),
+        };
+
+        if let Some(ready) = ready {
+            let len = handle.read_bulk(self.pipes.status, &mut status, timeout)?;
+
+            // the device may skip the data phase and fail the command right away
+            if status[0] != ready {
+                return Self::check_status(tag, &status[..len]);
+            }
+            if len < 4 || u16::from_be_bytes([status[2], status[3]]) != tag {
+                return Err(Error::InvalidUASTag);
+            }
+
+            let (pipe, res) = match &mut data {
+                Data::None => (self.pipes.data_in, Ok(0)),
+                Data::ToDevice(bfr) => (
+                    self.pipes.data_out,
+                    handle.write_bulk(self.pipes.data_out, bfr, timeout),
+                ),
+                Data::FromDevice(bfr) => (
+                    self.pipes.data_in,
+                    handle.read_bulk(self.pipes.data_in, bfr, timeout),
+                ),
+            };
+            transferred = res.map_err(|err| clear_stall(handle, pipe, Error::USB(err)));
+            debug!("data phase: {:?}", transferred);
+            if !status_follows(&transferred) {
+                return transferred.map(|_| ());
+            }
+        }
+
+        // a failed command usually cuts the data phase short, its sense data is the
+        // more useful error
+        let len = handle
+            .read_bulk(self.pipes.status, &mut status, timeout)
+            .map_err(|err| clear_stall(handle, self.pipes.status, Error::USB(err)))?;
+        Self::check_status(tag, &status[..len])?;
+        check_length(&data, transferred?)
+    }
+}
@@ -0,0 +1,74 @@
+/*
+ *  asm2x6xtool - configuration and firmware management for ASM2x6x chips
+ *  Copyright (C) 2024 Sven Peter <sven@svenpeter.dev>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use asm2x6xtool::asm2x6x::convert::{self, Format};
+use asm2x6xtool::asm2x6x::firmware::Firmware;
+use asm2x6xtool::asm2x6x::Model;
+use asm2x6xtool::error::Error;
+use asm2x6xtool::sim;
+
+fn firmware() -> Firmware {
+    let info = sim::DeviceInfo::default();
+    let flash = info.state.lock().unwrap().flash.clone();
+    Firmware::new(flash, Model::ASM2464PD)
+}
+
+#[test]
+fn hex_round_trip() {
+    let firmware = firmware();
+    let hex = convert::write_hex(&firmware).unwrap();
+
+    assert!(hex.starts_with(":020000040000FA\n"));
+    assert!(hex.contains(":020000040001F9\n"));
+    assert!(hex.ends_with(":00000001FF\n"));
+    assert_eq!(Format::detect(hex.as_bytes()), Format::IntelHex);
+
+    let parsed = convert::read_hex(&hex, Model::ASM2464PD).unwrap();
+    assert_eq!(parsed.as_bytes(), firmware.as_bytes());
+}
+
+#[test]
+fn hex_errors() {
+    let hex = convert::write_hex(&firmware()).unwrap();
+
+    // flip a data digit in the second line without fixing the record checksum
+    let mut lines: Vec<String> = hex.lines().map(String::from).collect();
+    let digit = if &lines[1][9..10] == "0" { "1" } else { "0" };
+    lines[1].replace_range(9..10, digit);
+    assert!(matches!(
+        convert::read_hex(&lines.join("\n"), Model::ASM2464PD),
+        Err(Error::InvalidHex(2))
+    ));
+
+    // the second data record is missing
+    let mut lines: Vec<&str> = hex.lines().collect();
+    lines.remove(2);
+    assert!(matches!(
+        convert::read_hex(&lines.join("\n"), Model::ASM2464PD),
+        Err(Error::MissingFirmwareData(0x10))
+    ));
+}
+
+#[test]
+fn hex_needs_whole_image() {
+    for len in [0x100, 0x17ee1] {
+        assert!(matches!(
+            convert::write_hex(&Firmware::new(vec![0; len], Model::ASM2464PD)),
+            Err(Error::InvalidFirmwareSize(l)) if l == len
+        ));
+    }
+}
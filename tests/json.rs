@@ -0,0 +1,35 @@
+/*
+ *  asm2x6xtool - configuration and firmware management for ASM2x6x chips
+ *  Copyright (C) 2024 Sven Peter <sven@svenpeter.dev>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use asm2x6xtool::json::{self, Value};
+
+#[test]
+fn serializes_nested_values() {
+    let value = json::object([
+        ("name", "sg:/dev/sg0 \"usb\"\n".into()),
+        ("speed", Option::<String>::None.into()),
+        ("ports", vec![1_u8, 2].into()),
+        ("data", json::hex(&[0xde, 0xad])),
+        ("ok", true.into()),
+        ("empty", Value::Object(Vec::new())),
+    ]);
+
+    assert_eq!(
+        value.to_string(),
+        r#"{"name":"sg:/dev/sg0 \"usb\"\n","speed":null,"ports":[1,2],"data":"dead","ok":true,"empty":{}}"#
+    );
+}
@@ -0,0 +1,146 @@
+/*
+ *  asm2x6xtool - configuration and firmware management for ASM2x6x chips
+ *  Copyright (C) 2024 Sven Peter <sven@svenpeter.dev>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use asm2x6xtool::asm2x6x::policy::TransferPolicy;
+use asm2x6xtool::asm2x6x::{run_parallel, Backend, Device, Info, Model};
+use asm2x6xtool::error::Error;
+use asm2x6xtool::sim;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+fn open(backend: Box<dyn Backend>) -> Device {
+    let mut device = Device::new(backend);
+    device.set_policy(TransferPolicy {
+        flash_delay: Duration::ZERO,
+        ..Default::default()
+    });
+    device
+}
+
+/// Holds the first transfer of every device until all devices have started one. If the
+/// devices were driven one after the other, the first one would wait in vain.
+struct Rendezvous {
+    devices: usize,
+    arrived: Mutex<usize>,
+    all_arrived: Condvar,
+}
+
+impl Rendezvous {
+    /// Returns false if not all devices arrived, the timeout only bounds a failing test.
+    fn wait(&self) -> bool {
+        let mut arrived = self.arrived.lock().unwrap();
+        *arrived += 1;
+        self.all_arrived.notify_all();
+
+        let (arrived, _) = self
+            .all_arrived
+            .wait_timeout_while(arrived, Duration::from_secs(10), |arrived| {
+                *arrived < self.devices
+            })
+            .unwrap();
+        *arrived >= self.devices
+    }
+}
+
+struct Meeting {
+    backend: Box<dyn Backend>,
+    rendezvous: Option<Arc<Rendezvous>>,
+    met: Arc<Mutex<Vec<bool>>>,
+}
+
+impl Meeting {
+    fn meet(&mut self) {
+        if let Some(rendezvous) = self.rendezvous.take() {
+            let met = rendezvous.wait();
+            self.met.lock().unwrap().push(met);
+        }
+    }
+}
+
+impl Backend for Meeting {
+    fn model(&self) -> Model {
+        self.backend.model()
+    }
+
+    fn transfer(&mut self, cdb: &[u8]) -> Result<(), Error> {
+        self.meet();
+        self.backend.transfer(cdb)
+    }
+
+    fn transfer_to_device(&mut self, cdb: &[u8], data: &[u8]) -> Result<(), Error> {
+        self.meet();
+        self.backend.transfer_to_device(cdb, data)
+    }
+
+    fn transfer_from_device(&mut self, cdb: &[u8], data: &mut [u8]) -> Result<(), Error> {
+        self.meet();
+        self.backend.transfer_from_device(cdb, data)
+    }
+
+    fn transfer_reset(&mut self, cdb: &[u8]) -> Result<(), Error> {
+        self.meet();
+        self.backend.transfer_reset(cdb)
+    }
+}
+
+#[test]
+fn devices_run_concurrently() {
+    let infos: Vec<_> = (0..8).map(|_| sim::DeviceInfo::default()).collect();
+    let rendezvous = Arc::new(Rendezvous {
+        devices: infos.len(),
+        arrived: Mutex::new(0),
+        all_arrived: Condvar::new(),
+    });
+    let met = Arc::new(Mutex::new(Vec::new()));
+
+    let mut devices: Vec<_> = infos
+        .iter()
+        .map(|info| {
+            open(Box::new(Meeting {
+                backend: info.open().unwrap(),
+                rendezvous: Some(rendezvous.clone()),
+                met: met.clone(),
+            }))
+        })
+        .collect();
+
+    // every read only gets past its first transfer once all of them have started
+    let images = run_parallel(&mut devices, |_, device| device.read_firmware());
+    assert_eq!(*met.lock().unwrap(), vec![true; infos.len()]);
+
+    for (image, info) in images.into_iter().zip(infos.iter()) {
+        assert_eq!(image.unwrap(), info.state.lock().unwrap().flash);
+    }
+}
+
+#[test]
+fn results_keep_the_device_order() {
+    let mut devices: Vec<_> = (0..4)
+        .map(|_| open(sim::DeviceInfo::default().open().unwrap()))
+        .collect();
+
+    let results = run_parallel(&mut devices, |i, device| {
+        device.write(0x1000, i as u8)?;
+        let mut bfr = [0_u8; 1];
+        device.read(0x1000, &mut bfr)?;
+        Ok::<_, Error>((i, bfr[0]))
+    });
+
+    for (i, result) in results.into_iter().enumerate() {
+        assert_eq!(result.unwrap(), (i, i as u8));
+    }
+}
@@ -0,0 +1,77 @@
+/*
+ *  asm2x6xtool - configuration and firmware management for ASM2x6x chips
+ *  Copyright (C) 2024 Sven Peter <sven@svenpeter.dev>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use asm2x6xtool::asm2x6x::Info;
+use asm2x6xtool::error::Error;
+use asm2x6xtool::scsi::{Sense, SenseKey};
+use asm2x6xtool::sim;
+
+#[test]
+fn fixed_format() {
+    let mut bfr = [0_u8; 20];
+    bfr[0] = 0xf0;
+    bfr[2] = 0x05;
+    bfr[3..7].copy_from_slice(&0x1234_u32.to_be_bytes());
+    bfr[7] = 12;
+    bfr[12] = 0x20;
+    bfr[18..20].copy_from_slice(&[0xaa, 0xbb]);
+
+    let sense = Sense::parse(&bfr).unwrap();
+    assert_eq!(sense.key, SenseKey::IllegalRequest);
+    assert_eq!((sense.asc, sense.ascq), (0x20, 0x00));
+    assert_eq!(sense.information, Some(0x1234));
+    assert_eq!(sense.vendor, [0xaa, 0xbb]);
+    assert!(sense.is_unsupported_command());
+    assert!(!sense.deferred);
+}
+
+#[test]
+fn descriptor_format() {
+    let bfr = [
+        0x73, 0x02, 0x04, 0x07, 0, 0, 0, 18, // header
+        0x00, 0x0a, 0x80, 0, 0, 0, 0, 0, 0, 0, 0x56, 0x78, // information
+        0x80, 0x02, 0x11, 0x22, // vendor specific
+    ];
+
+    let sense = Sense::parse(&bfr).unwrap();
+    assert_eq!(sense.key, SenseKey::NotReady);
+    assert!(sense.is_not_ready());
+    assert!(sense.deferred);
+    assert_eq!(sense.information, Some(0x5678));
+    assert_eq!(sense.vendor, [0x80, 0x02, 0x11, 0x22]);
+    assert_eq!(
+        sense.to_string(),
+        "NOT READY: logical unit not ready, operation in progress (asc 0x04, ascq 0x07), \
+         deferred, information 0x5678, vendor data [80, 02, 11, 22]"
+    );
+}
+
+#[test]
+fn invalid_response_code() {
+    assert_eq!(Sense::parse(&[]), None);
+    assert_eq!(Sense::parse(&[0x00; 18]), None);
+}
+
+#[test]
+fn sim_reports_unsupported_command() {
+    let mut backend = sim::DeviceInfo::default().open().unwrap();
+
+    match backend.transfer(&[0x12, 0, 0, 0, 0, 0]) {
+        Err(Error::Sense(sense)) => assert!(sense.is_unsupported_command()),
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
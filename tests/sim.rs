@@ -0,0 +1,560 @@
+/*
+ *  asm2x6xtool - configuration and firmware management for ASM2x6x chips
+ *  Copyright (C) 2024 Sven Peter <sven@svenpeter.dev>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use asm2x6xtool::asm2x6x::config::{Config, CONFIG_SIZE};
+use asm2x6xtool::asm2x6x::filter::Filter;
+use asm2x6xtool::asm2x6x::firmware::{Firmware, Problem};
+use asm2x6xtool::asm2x6x::policy::TransferPolicy;
+use asm2x6xtool::asm2x6x::snapshot::Snapshot;
+use asm2x6xtool::asm2x6x::update::update_firmware;
+use asm2x6xtool::asm2x6x::{Backend, Device, DeviceDetails, FWVersion, Info, Model, XDATA_SIZE};
+use asm2x6xtool::error::Error;
+use asm2x6xtool::sim;
+use std::time::Duration;
+
+// the simulator does not need any delays between commands
+const FAST: TransferPolicy = TransferPolicy {
+    register_timeout: Duration::from_secs(1),
+    config_timeout: Duration::from_secs(1),
+    flash_timeout: Duration::from_secs(1),
+    reset_timeout: Duration::from_secs(1),
+    retries: 2,
+    backoff: Duration::ZERO,
+    command_delay: Duration::ZERO,
+    flash_delay: Duration::ZERO,
+    reconnect_delay: Duration::ZERO,
+};
+
+fn open() -> (sim::DeviceInfo, Device) {
+    let info = sim::DeviceInfo::default();
+    let mut device = Device::new(info.open().unwrap());
+    device.set_policy(FAST);
+    (info, device)
+}
+
+/// Fails the first `failures` transfers with a timeout before passing them on.
+struct Flaky {
+    backend: Box<dyn Backend>,
+    failures: usize,
+}
+
+impl Flaky {
+    fn fail(&mut self) -> Result<(), Error> {
+        if self.failures > 0 {
+            self.failures -= 1;
+            return Err(Error::USB(rusb::Error::Timeout));
+        }
+        Ok(())
+    }
+}
+
+impl Backend for Flaky {
+    fn model(&self) -> Model {
+        self.backend.model()
+    }
+
+    fn transfer(&mut self, cdb: &[u8]) -> Result<(), Error> {
+        self.fail()?;
+        self.backend.transfer(cdb)
+    }
+
+    fn transfer_to_device(&mut self, cdb: &[u8], data: &[u8]) -> Result<(), Error> {
+        self.fail()?;
+        self.backend.transfer_to_device(cdb, data)
+    }
+
+    fn transfer_from_device(&mut self, cdb: &[u8], data: &mut [u8]) -> Result<(), Error> {
+        self.fail()?;
+        self.backend.transfer_from_device(cdb, data)
+    }
+
+    fn transfer_reset(&mut self, cdb: &[u8]) -> Result<(), Error> {
+        self.fail()?;
+        self.backend.transfer_reset(cdb)
+    }
+}
+
+/// Flips a bit in the data of the first `corruptions` flash writes.
+struct CorruptFlash {
+    backend: Box<dyn Backend>,
+    corruptions: usize,
+}
+
+impl Backend for CorruptFlash {
+    fn model(&self) -> Model {
+        self.backend.model()
+    }
+
+    fn transfer(&mut self, cdb: &[u8]) -> Result<(), Error> {
+        self.backend.transfer(cdb)
+    }
+
+    fn transfer_to_device(&mut self, cdb: &[u8], data: &[u8]) -> Result<(), Error> {
+        if cdb[0] != 0xe3 || self.corruptions == 0 {
+            return self.backend.transfer_to_device(cdb, data);
+        }
+
+        self.corruptions -= 1;
+        let mut data = data.to_vec();
+        data[0x10] ^= 0x01;
+        self.backend.transfer_to_device(cdb, &data)
+    }
+
+    fn transfer_from_device(&mut self, cdb: &[u8], data: &mut [u8]) -> Result<(), Error> {
+        self.backend.transfer_from_device(cdb, data)
+    }
+
+    fn transfer_reset(&mut self, cdb: &[u8]) -> Result<(), Error> {
+        self.backend.transfer_reset(cdb)
+    }
+}
+
+/// Version the simulator reports after booting `new_firmware`.
+fn new_version() -> FWVersion {
+    "240630_01_02_03".parse().unwrap()
+}
+
+/// An image that differs from the simulator's firmware in its version.
+fn new_firmware(device: &mut Device) -> Firmware {
+    let mut bfr = device.read_firmware().unwrap();
+    bfr[sim::VERSION_OFFSET..sim::VERSION_OFFSET + 6]
+        .copy_from_slice(&[0x24, 0x06, 0x30, 0x01, 0x02, 0x03]);
+    Firmware::new(bfr, device.model())
+}
+
+#[test]
+fn memory_write_then_read() {
+    let (_, mut device) = open();
+
+    device.write(0x1234, 0xab).unwrap();
+    device.write(0x1235, 0xcd).unwrap();
+
+    let mut bfr = [0_u8; 2];
+    device.read(0x1234, &mut bfr).unwrap();
+    assert_eq!(bfr, [0xab, 0xcd]);
+}
+
+#[test]
+fn memory_large_read() {
+    let (info, mut device) = open();
+
+    for (i, b) in info.state.lock().unwrap().xdata.iter_mut().enumerate() {
+        *b = (i % 251) as u8;
+    }
+
+    let mut calls = Vec::new();
+    let mut bfr = vec![0_u8; XDATA_SIZE as usize];
+    device
+        .read_with_progress(0, &mut bfr, |done, total| calls.push((done, total)))
+        .unwrap();
+
+    assert!(bfr.iter().enumerate().all(|(i, &b)| b == (i % 251) as u8));
+    assert_eq!(calls.last(), Some(&(bfr.len(), bfr.len())));
+    assert!(calls.len() > 1);
+}
+
+#[test]
+fn memory_out_of_range() {
+    let (_, mut device) = open();
+
+    let mut bfr = [0_u8; 0x10];
+    assert!(matches!(
+        device.read(XDATA_SIZE - 8, &mut bfr),
+        Err(Error::InvalidAddress(_, 0x10))
+    ));
+    assert!(matches!(
+        device.write(XDATA_SIZE, 0x00),
+        Err(Error::InvalidAddress(_, 1))
+    ));
+    assert!(matches!(
+        Device::check_xdata_range(u32::MAX, 2),
+        Err(Error::InvalidAddress(u32::MAX, 2))
+    ));
+    assert!(Device::check_xdata_range(XDATA_SIZE - 2, 2).is_ok());
+}
+
+#[test]
+fn fw_version_fields() {
+    let (_, mut device) = open();
+    let version = device.read_fw_version().unwrap();
+
+    assert_eq!(version.build_date(), Some((2024, 1, 15)));
+    assert_eq!((version.a(), version.b(), version.c()), (0x00, 0x01, 0x00));
+    assert_eq!(device.transport(), "simulator");
+}
+
+#[test]
+fn default_config_is_valid() {
+    let (_, mut device) = open();
+
+    let config = Config::new(&device.read_config().unwrap(), device.model());
+    assert!(!config.layout_verified());
+    assert!(config.to_string().starts_with("layout:      unverified"));
+    assert_eq!((config.vid, config.pid), (0x174c, 0x2463));
+    assert_eq!(config.to_bytes().unwrap(), device.read_config().unwrap());
+}
+
+#[test]
+fn config_write_round_trip() {
+    let (info, mut device) = open();
+
+    let mut config = Config::new(&device.read_config().unwrap(), device.model());
+    config.serial = "CI-0001".into();
+    config.pid = 0x1234;
+    let bfr = config.to_bytes().unwrap();
+
+    device.write_config(&bfr).unwrap();
+    assert_eq!(info.state.lock().unwrap().config, bfr);
+
+    let config = Config::new(&device.read_config().unwrap(), device.model());
+    assert_eq!(config.serial, "CI-0001");
+    assert_eq!(config.pid, 0x1234);
+}
+
+#[test]
+fn config_round_trip_is_lossless() {
+    // not built by the simulator, with non-ASCII bytes in the string fields
+    let bfr: [u8; CONFIG_SIZE] = std::array::from_fn(|i| (i as u8).wrapping_mul(37) ^ 0xa5);
+
+    let config = Config::new(&bfr, Model::ASM2464PD);
+    assert_eq!(config.to_bytes().unwrap(), bfr);
+
+    let mut config = Config::parse(&bfr, Model::ASM2464PD).unwrap();
+    config.pid = 0x1234;
+    let changed = config.to_bytes().unwrap();
+    assert_eq!(changed[CONFIG_SIZE - 1], bfr[CONFIG_SIZE - 1]);
+    assert_eq!(
+        changed
+            .iter()
+            .zip(bfr.iter())
+            .filter(|(a, b)| a != b)
+            .count(),
+        2
+    );
+}
+
+#[test]
+fn config_rejects_overlong_string() {
+    let (_, mut device) = open();
+
+    let mut config = Config::new(&device.read_config().unwrap(), device.model());
+    config.vendor = "x".repeat(64);
+    assert!(matches!(
+        config.to_bytes(),
+        Err(Error::InvalidConfigField("vendor"))
+    ));
+}
+
+#[test]
+fn firmware_write_read_reload() {
+    let (info, mut device) = open();
+
+    let version = [0x24, 0x06, 0x30, 0x01, 0x02, 0x03];
+    let mut bfr = device.read_firmware().unwrap();
+    bfr[sim::VERSION_OFFSET..sim::VERSION_OFFSET + 6].copy_from_slice(&version);
+    let firmware = Firmware::new(bfr, device.model());
+    assert!(firmware.validate().is_empty());
+
+    device.write_firmware(firmware.as_bytes()).unwrap();
+    assert_eq!(device.read_firmware().unwrap(), firmware.as_bytes());
+
+    // the running version only changes once the chip reboots
+    assert_ne!(device.read_fw_version().unwrap(), FWVersion::from(version));
+    device.reload().unwrap();
+    assert_eq!(info.state.lock().unwrap().reloads, 1);
+    assert_eq!(device.read_fw_version().unwrap(), FWVersion::from(version));
+}
+
+#[test]
+fn firmware_write_rejects_wrong_size() {
+    let (_, mut device) = open();
+
+    assert!(matches!(
+        device.write_firmware(&[0_u8; 0x100]),
+        Err(Error::InvalidFirmwareSize(0x100))
+    ));
+    assert_eq!(
+        Firmware::new(vec![0_u8; 0x17ee1], Model::ASM2464PD).validate(),
+        vec![Problem::InvalidSize {
+            expected: 0x17ee0,
+            found: 0x17ee1
+        }]
+    );
+}
+
+#[test]
+fn model_table_lookups() {
+    assert_eq!(Model::from_usb_id(0x174c, 0x2463), Some(Model::ASM2464PD));
+    assert_eq!(Model::from_usb_id(0x174c, 0x2464), None);
+    assert_eq!(Model::from_usb_id(0x1234, 0x2463), None);
+    assert_eq!(
+        Model::from_inquiry("ASMT    ", "ASM246X"),
+        Some(Model::ASM2464PD)
+    );
+    assert_eq!("asm2464pd".parse::<Model>().unwrap(), Model::ASM2464PD);
+    assert!(matches!(
+        "asm2463".parse::<Model>(),
+        Err(Error::UnknownModel)
+    ));
+}
+
+#[test]
+fn snapshot_round_trip_and_diff() {
+    let (_, mut device) = open();
+
+    let before = Snapshot::capture(&mut device, |_, _| {}).unwrap();
+    device.write(0x1000, 0x11).unwrap();
+    device.write(0x1001, 0x22).unwrap();
+    device.write(0x2000, 0x33).unwrap();
+    let after = Snapshot::capture(&mut device, |_, _| {}).unwrap();
+
+    let mut file = Vec::new();
+    after.write_to(&mut file).unwrap();
+    let after = Snapshot::read_from(file.as_slice()).unwrap();
+    assert_eq!(after.model, Model::ASM2464PD);
+    assert_eq!(after.version, device.read_fw_version().unwrap());
+
+    let changes = before.diff(&after);
+    assert_eq!(changes.len(), 2);
+    assert_eq!(changes[0].addr, 0x1000);
+    assert_eq!(changes[0].new, [0x11, 0x22]);
+    assert_eq!(changes[1].addr, 0x2000);
+    assert_eq!(changes[1].old, [0x00]);
+}
+
+#[test]
+fn snapshot_rejects_oversized_header() {
+    let (_, mut device) = open();
+
+    let mut file = Vec::new();
+    Snapshot::capture(&mut device, |_, _| {})
+        .unwrap()
+        .write_to(&mut file)
+        .unwrap();
+
+    let size = format!("size: {}\n", XDATA_SIZE);
+    let start = file
+        .windows(size.len())
+        .position(|w| w == size.as_bytes())
+        .unwrap();
+    file.splice(
+        start..start + size.len(),
+        format!("size: {}\n", usize::MAX).into_bytes(),
+    );
+
+    assert!(matches!(
+        Snapshot::read_from(file.as_slice()),
+        Err(Error::InvalidSnapshot)
+    ));
+}
+
+#[test]
+fn config_and_flash_reads_are_retried() {
+    let info = sim::DeviceInfo::default();
+    let flaky = |failures| {
+        let mut device = Device::new(Box::new(Flaky {
+            backend: info.open().unwrap(),
+            failures,
+        }));
+        device.set_policy(FAST);
+        device
+    };
+
+    assert!(flaky(2).read_config().is_ok());
+    assert!(flaky(2).read_firmware().is_ok());
+}
+
+#[test]
+fn xdata_reads_are_not_retried() {
+    let info = sim::DeviceInfo::default();
+    let backend = Flaky {
+        backend: info.open().unwrap(),
+        failures: 1,
+    };
+    let mut device = Device::new(Box::new(backend));
+    device.set_policy(FAST);
+
+    assert!(matches!(
+        device.read_fw_version(),
+        Err(Error::USB(rusb::Error::Timeout))
+    ));
+}
+
+#[test]
+fn writes_are_not_retried() {
+    let info = sim::DeviceInfo::default();
+    let backend = Flaky {
+        backend: info.open().unwrap(),
+        failures: 1,
+    };
+    let mut device = Device::new(Box::new(backend));
+    device.set_policy(FAST);
+
+    assert!(matches!(
+        device.write(0x1000, 0x42),
+        Err(Error::USB(rusb::Error::Timeout))
+    ));
+    assert_eq!(info.state.lock().unwrap().xdata[0x1000], 0x00);
+}
+
+#[test]
+fn physical_id_prefers_port_path() {
+    let details = DeviceDetails {
+        usb_id: Some((0x174c, 0x2463)),
+        serial: Some(String::from("000000000001")),
+        port_path: Some(String::from("2-1.3")),
+        ..DeviceDetails::default()
+    };
+    assert_eq!(details.physical_id().as_deref(), Some("port 2-1.3"));
+
+    let without_port = DeviceDetails {
+        port_path: None,
+        ..details.clone()
+    };
+    assert_eq!(
+        without_port.physical_id().as_deref(),
+        Some("174c:2463 000000000001")
+    );
+
+    let without_serial = DeviceDetails {
+        serial: None,
+        ..without_port
+    };
+    assert_eq!(without_serial.physical_id(), None);
+}
+
+#[test]
+fn filter_selects_by_model_firmware_and_serial() {
+    let (info, mut device) = open();
+    let details = DeviceDetails::default();
+    let version = device.read_fw_version().unwrap();
+
+    let filter = Filter {
+        model: Some(Model::ASM2464PD),
+        firmware: Some(version),
+        serial: Some(String::from("000000000001")),
+        ..Filter::default()
+    };
+    assert!(filter.matches_info(&info));
+    assert!(filter.matches_device(&mut device, &details).unwrap());
+
+    // the simulator has no USB port
+    let port = Filter {
+        port_path: Some(String::from("2-1")),
+        ..Filter::default()
+    };
+    assert!(!port.matches_info(&info));
+
+    let other_serial = Filter {
+        serial: Some(String::from("000000000002")),
+        ..Filter::default()
+    };
+    assert!(!other_serial.matches_device(&mut device, &details).unwrap());
+}
+
+#[test]
+fn update_installs_and_verifies_firmware() {
+    let (info, mut device) = open();
+    let original = device.read_firmware().unwrap();
+    let firmware = new_firmware(&mut device);
+
+    let mut backup = None;
+    let mut device = update_firmware(
+        device,
+        &firmware,
+        Some(new_version()),
+        |b| {
+            backup = Some(b.firmware.clone());
+            Ok(())
+        },
+        || Ok(Device::new(info.open()?)),
+    )
+    .unwrap();
+
+    assert_eq!(backup.unwrap(), original);
+    assert_eq!(device.read_fw_version().unwrap(), new_version());
+    assert_eq!(info.state.lock().unwrap().reloads, 1);
+}
+
+#[test]
+fn update_restores_backup_if_verification_fails() {
+    let info = sim::DeviceInfo::default();
+    let mut device = Device::new(Box::new(CorruptFlash {
+        backend: info.open().unwrap(),
+        corruptions: 1,
+    }));
+    device.set_policy(FAST);
+    let original = device.read_firmware().unwrap();
+    let firmware = new_firmware(&mut device);
+
+    let result = update_firmware(
+        device,
+        &firmware,
+        None,
+        |_| Ok(()),
+        || Ok(Device::new(info.open()?)),
+    );
+
+    match result {
+        Err(Error::UpdateRolledBack(err)) => {
+            assert!(matches!(*err, Error::FirmwareVerifyFailed))
+        }
+        _ => panic!("update was not rolled back"),
+    }
+    assert_eq!(info.state.lock().unwrap().flash, original);
+}
+
+#[test]
+fn update_restores_backup_if_version_does_not_match() {
+    let (info, mut device) = open();
+    let original = device.read_firmware().unwrap();
+    let firmware = new_firmware(&mut device);
+
+    let result = update_firmware(
+        device,
+        &firmware,
+        Some("990101_00_00_00".parse().unwrap()),
+        |_| Ok(()),
+        || Ok(Device::new(info.open()?)),
+    );
+
+    match result {
+        Err(Error::UpdateRolledBack(err)) => {
+            assert!(matches!(*err, Error::UnexpectedFWVersion { .. }))
+        }
+        _ => panic!("update was not rolled back"),
+    }
+    assert_eq!(info.state.lock().unwrap().flash, original);
+    assert_eq!(info.state.lock().unwrap().reloads, 2);
+}
+
+#[test]
+fn update_rejects_invalid_image() {
+    let (info, device) = open();
+    let firmware = Firmware::new(vec![0xff; 0x17ee0], device.model());
+
+    let result = update_firmware(
+        device,
+        &firmware,
+        None,
+        |_| panic!("no backup needed for an invalid image"),
+        || Ok(Device::new(info.open()?)),
+    );
+
+    assert!(matches!(result, Err(Error::InvalidFirmware)));
+    assert_eq!(info.state.lock().unwrap().reloads, 0);
+}
@@ -0,0 +1,149 @@
+/*
+ *  asm2x6xtool - configuration and firmware management for ASM2x6x chips
+ *  Copyright (C) 2024 Sven Peter <sven@svenpeter.dev>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use asm2x6xtool::asm2x6x::policy::TransferPolicy;
+use asm2x6xtool::asm2x6x::{Device, Info};
+use asm2x6xtool::error::Error;
+use asm2x6xtool::{sim, trace};
+use std::fs::File;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[test]
+fn record_and_replay() {
+    let path = std::env::temp_dir().join(format!("asm2x6x-{}.trace", std::process::id()));
+
+    let backend = sim::DeviceInfo::default().open().unwrap();
+    let recorder = trace::Recorder::new(backend, Box::new(File::create(&path).unwrap())).unwrap();
+    let mut device = Device::new(Box::new(recorder));
+
+    device.write(0x1000, 0x5a).unwrap();
+    let mut recorded = [0_u8; 0x10];
+    device.read(0x1000, &mut recorded).unwrap();
+    let config = device.read_config().unwrap();
+    drop(device);
+
+    let info = trace::DeviceInfo::new(&path).unwrap();
+    assert_eq!(info.model(), sim::DeviceInfo::default().model());
+
+    let mut device = Device::new(info.open().unwrap());
+    device.write(0x1000, 0x5a).unwrap();
+    let mut replayed = [0_u8; 0x10];
+    device.read(0x1000, &mut replayed).unwrap();
+    assert_eq!(replayed, recorded);
+    assert_eq!(device.read_config().unwrap(), config);
+
+    // anything that diverges from the recording is rejected
+    assert!(matches!(
+        device.write(0x1000, 0x00),
+        Err(Error::TraceMismatch(_))
+    ));
+
+    let mut device = Device::new(info.open().unwrap());
+    assert!(matches!(
+        device.write(0x1000, 0xa5),
+        Err(Error::TraceMismatch(1))
+    ));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn resumed_recorder_appends() {
+    let path = std::env::temp_dir().join(format!("asm2x6x-resume-{}.trace", std::process::id()));
+    let out = trace::SharedWriter::new(Box::new(File::create(&path).unwrap()));
+
+    let backend = sim::DeviceInfo::default().open().unwrap();
+    let recorder = trace::Recorder::new(backend, Box::new(out.clone())).unwrap();
+    Device::new(Box::new(recorder)).write(0x1000, 0x5a).unwrap();
+
+    let backend = sim::DeviceInfo::default().open().unwrap();
+    let recorder = trace::Recorder::resume(backend, Box::new(out));
+    Device::new(Box::new(recorder)).write(0x1001, 0xa5).unwrap();
+
+    let info = trace::DeviceInfo::new(&path).unwrap();
+    let mut device = Device::new(info.open().unwrap());
+    device.write(0x1000, 0x5a).unwrap();
+    device.write(0x1001, 0xa5).unwrap();
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn transient_errors_are_retried_on_replay() {
+    let path = std::env::temp_dir().join(format!("asm2x6x-retry-{}.trace", std::process::id()));
+    let config: String = (0..0x80).map(|i| format!("{:02x}", i)).collect();
+    std::fs::write(
+        &path,
+        format!(
+            "asm2x6x-trace 1\n\
+             model ASM2464PD\n\
+             from e05000000000 - {} transient USB error: Operation timed out\n\
+             from e05000000000 - {} ok\n",
+            config, config
+        ),
+    )
+    .unwrap();
+
+    let info = trace::DeviceInfo::new(&path).unwrap();
+    let mut device = Device::new(info.open().unwrap());
+    device.set_policy(TransferPolicy {
+        backoff: Duration::ZERO,
+        command_delay: Duration::ZERO,
+        ..Default::default()
+    });
+    let config = device.read_config().unwrap();
+    assert!(config.iter().enumerate().all(|(i, &b)| b == i as u8));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// Accepts the trace header, then fails every write once `broken` is set.
+struct BrokenWriter {
+    broken: Arc<AtomicBool>,
+}
+
+impl Write for BrokenWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.broken.load(Ordering::SeqCst) {
+            true => Err(std::io::Error::other("disk full")),
+            false => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn recorder_failure_keeps_transfer_result() {
+    let broken = Arc::new(AtomicBool::new(false));
+    let out = BrokenWriter {
+        broken: broken.clone(),
+    };
+
+    let info = sim::DeviceInfo::default();
+    let recorder = trace::Recorder::new(info.open().unwrap(), Box::new(out)).unwrap();
+    let mut device = Device::new(Box::new(recorder));
+    broken.store(true, Ordering::SeqCst);
+
+    device.write(0x1000, 0x5a).unwrap();
+    assert_eq!(info.state.lock().unwrap().xdata[0x1000], 0x5a);
+}
@@ -0,0 +1,49 @@
+/*
+ *  asm2x6xtool - configuration and firmware management for ASM2x6x chips
+ *  Copyright (C) 2024 Sven Peter <sven@svenpeter.dev>
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, version 3.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use asm2x6xtool::usb::find_extra_descriptor;
+
+const PIPE_USAGE: u8 = 0x24;
+const SS_ENDPOINT_COMPANION: u8 = 0x30;
+
+#[test]
+fn pipe_usage_after_companion() {
+    // SuperSpeed UAS endpoint: the companion descriptor comes before the pipe usage
+    let extra = [6, SS_ENDPOINT_COMPANION, 15, 4, 0, 0, 4, PIPE_USAGE, 2, 0];
+    assert_eq!(
+        find_extra_descriptor(&extra, PIPE_USAGE),
+        Some(&[4, PIPE_USAGE, 2, 0][..])
+    );
+
+    // high speed: the pipe usage descriptor is the only one
+    let extra = [4, PIPE_USAGE, 1, 0];
+    assert_eq!(find_extra_descriptor(&extra, PIPE_USAGE), Some(&extra[..]));
+}
+
+#[test]
+fn malformed_extra_descriptors() {
+    assert_eq!(find_extra_descriptor(&[], PIPE_USAGE), None);
+    assert_eq!(
+        find_extra_descriptor(&[6, SS_ENDPOINT_COMPANION, 15], PIPE_USAGE),
+        None
+    );
+    // a zero length would never advance
+    assert_eq!(
+        find_extra_descriptor(&[0, 0, 4, PIPE_USAGE, 2, 0], PIPE_USAGE),
+        None
+    );
+}